@@ -0,0 +1,229 @@
+use crate::config::Config;
+use crate::context;
+use crate::gemini::{GeminiClient, ProposedEdit, WriteOptions};
+use crate::git;
+use crate::guard;
+use crate::output::{self, Palette};
+use crate::pager;
+use crate::report::{line_stats, FileChange};
+use crate::spinner::Spinner;
+use crate::structured::DataFormat;
+use crate::undo::{self, PendingWrite};
+use dialoguer::{MultiSelect, Select, theme::ColorfulTheme};
+use std::io::{self, IsTerminal};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+fn describe(edit: &ProposedEdit) -> String {
+    let Palette { red, blue, reset, .. } = output::palette();
+    let (added, removed) = line_stats(&edit.original, &edit.new_content);
+    format!("{}  {}+{}{} {}-{}{}", edit.path, blue, added, reset, red, removed, reset)
+}
+
+/// Runs one instruction over every file matching `pattern`, at most
+/// `batch_concurrency` at a time, and writes the approved changes as a
+/// single operation that `huh --undo` can revert. Each file goes through
+/// the same checks and repairs as a single edit. Returns the files written.
+pub async fn run(
+    client: Arc<GeminiClient>,
+    pattern: &str,
+    context: &str,
+    options: &WriteOptions,
+    config: &Config,
+) -> Result<Vec<FileChange>, String> {
+    // Approval is interactive, so fail before any requests are made
+    if !io::stdin().is_terminal() {
+        return Err("Batch edits must be approved interactively; no changes were written".to_string());
+    }
+
+    let mut targets = Vec::new();
+    for path in context::resolve_reference(pattern)? {
+        let path = path.strip_prefix("./").unwrap_or(&path).to_string_lossy().to_string();
+        let problems = guard::check_write_path(&path, config, &options.allowed_paths);
+        if !problems.is_empty() {
+            output::warn(&format!("Skipping {} because {}", path, problems.join(", and ")));
+            continue;
+        }
+        if let Some(status) = git::file_status(&path) {
+            if let Err(e) = client.check_git_status(&path, true, &status) {
+                output::warn(&format!("Skipping {}: {}", path, e));
+                continue;
+            }
+        }
+        targets.push(path);
+    }
+    if targets.is_empty() {
+        return Err(format!("No files matching @{} can be edited", pattern));
+    }
+
+    let limit = config.batch_concurrency.max(1);
+    let semaphore = Arc::new(Semaphore::new(limit));
+    let shared_options = Arc::new(options.clone());
+    let mut tasks = JoinSet::new();
+    for path in targets.iter().cloned() {
+        let client = Arc::clone(&client);
+        let semaphore = Arc::clone(&semaphore);
+        let options = Arc::clone(&shared_options);
+        let context = context.to_string();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            let result = client.propose_edit(&path, &context, &options).await;
+            (path, result)
+        });
+    }
+
+    let spinner = Spinner::start(&format!("Editing {} files, {} at a time...", targets.len(), limit));
+    let mut results = Vec::with_capacity(targets.len());
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok(result) => results.push(result),
+            Err(e) => {
+                spinner.stop().await;
+                return Err(format!("A batch task failed: {}", e));
+            }
+        }
+    }
+    spinner.stop().await;
+    results.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut edits = Vec::new();
+    let mut failures = Vec::new();
+    let mut unchanged = 0;
+    for (path, result) in results {
+        match result {
+            Ok(edit) if edit.original == edit.new_content => unchanged += 1,
+            Ok(edit) => edits.push(edit),
+            Err(e) => failures.push((path, e)),
+        }
+    }
+
+    if options.semantic_diff {
+        for edit in edits.iter().filter(|edit| DataFormat::for_path(&edit.path).is_some_and(|format| format.has_key_diff())) {
+            client.display_edit(edit, options);
+        }
+    }
+    print_summary(pattern, &edits, unchanged, &failures, options.git_diff, &config.pager);
+    if edits.is_empty() {
+        output::say("\nNo changes to apply");
+        return Ok(Vec::new());
+    }
+
+    let approved = approve(&edits)?;
+    let mut chosen = Vec::with_capacity(approved.len());
+    for edit in approved {
+        if !options.patch {
+            chosen.push((edit, edit.new_content.clone()));
+            continue;
+        }
+        output::say(format_args!("\nChoosing hunks for {}", edit.path));
+        match client.choose_hunks(edit).await {
+            Ok(Some(content)) => chosen.push((edit, content)),
+            Ok(None) => output::say(format_args!("No hunks selected; {} is left unchanged", edit.path)),
+            Err(e) => output::warn(&format!("Skipping {}: {}", edit.path, e)),
+        }
+    }
+    if chosen.is_empty() {
+        output::say("\nNo changes were written");
+        return Ok(Vec::new());
+    }
+
+    let writes = chosen
+        .iter()
+        .map(|(edit, content)| {
+            Ok(PendingWrite {
+                path: edit.path.clone(),
+                bytes: edit.encode(content)?,
+                snapshot: edit.snapshot.clone(),
+            })
+        })
+        .collect::<Result<Vec<PendingWrite>, String>>()?;
+    undo::apply_all(&writes, &format!("huh -w @{} \"{}\"", pattern, context))?;
+
+    output::say(format_args!(
+        "\n✓ Edited {} files. Run `huh --undo` to revert all of them.",
+        writes.len()
+    ));
+    Ok(chosen
+        .iter()
+        .map(|(edit, content)| FileChange::new(&edit.path, true, &edit.original, content))
+        .collect())
+}
+
+fn print_summary(
+    pattern: &str,
+    edits: &[ProposedEdit],
+    unchanged: usize,
+    failures: &[(String, String)],
+    full_diff: bool,
+    pager: &str,
+) {
+    let Palette { red, yellow, blue, bold, reset, .. } = output::palette();
+    if full_diff {
+        let diffs: String = edits
+            .iter()
+            .map(|edit| format!("\n{}\n", git::colorize_diff(&git::unified_diff(&edit.original, &edit.new_content, &edit.path))))
+            .collect();
+        let diffs = diffs.trim_end();
+        if !pager::page(diffs, pager) {
+            output::say(diffs);
+        }
+    }
+
+    output::say(format_args!("\n{}▲ Batch changes for {}:{}", bold, pattern, reset));
+    output::say("─────────────────────────────────────────────────────────────");
+    let (mut total_added, mut total_removed) = (0, 0);
+    for edit in edits {
+        let (added, removed) = line_stats(&edit.original, &edit.new_content);
+        total_added += added;
+        total_removed += removed;
+        output::say(format_args!("  {}", describe(edit)));
+    }
+    output::say(format_args!(
+        "\n  {} files changed, {}{}{}{} additions (+), {}{}{}{} deletions (-)",
+        edits.len(),
+        blue,
+        bold,
+        total_added,
+        reset,
+        red,
+        bold,
+        total_removed,
+        reset
+    ));
+    if unchanged > 0 {
+        output::say(format_args!("  {} files needed no changes", unchanged));
+    }
+    if !failures.is_empty() {
+        output::say(format_args!("\n  {}{} files failed:{}", yellow, failures.len(), reset));
+        for (path, error) in failures {
+            output::say(format_args!("    {}: {}", path, error));
+        }
+    }
+    output::say("─────────────────────────────────────────────────────────────");
+}
+
+fn approve(edits: &[ProposedEdit]) -> Result<Vec<&ProposedEdit>, String> {
+    let choice = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt("Apply these changes?")
+        .default(0)
+        .items(&["Apply all changes", "Choose files", "Cancel"])
+        .interact()
+        .map_err(|e| format!("Failed to get user selection: {}", e))?;
+
+    match choice {
+        0 => Ok(edits.iter().collect()),
+        1 => {
+            let items: Vec<String> = edits.iter().map(describe).collect();
+            let defaults = vec![true; edits.len()];
+            let selected = MultiSelect::with_theme(&ColorfulTheme::default())
+                .with_prompt("Files to change (space to toggle, enter to confirm)")
+                .items(&items)
+                .defaults(&defaults)
+                .interact()
+                .map_err(|e| format!("Failed to get user selection: {}", e))?;
+            Ok(selected.into_iter().map(|index| &edits[index]).collect())
+        }
+        _ => Ok(Vec::new()),
+    }
+}
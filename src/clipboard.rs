@@ -0,0 +1,117 @@
+use crate::markdown;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// What `--copy` and `--copy-block` put on the clipboard.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CopyTarget {
+    /// The "Did you mean" command
+    Suggestion,
+    /// A code block, numbered from 1
+    Block(usize),
+}
+
+/// Picks the text to copy out of a reply, given its suggested command and
+/// its markdown.
+pub fn select(target: CopyTarget, suggestion: Option<&str>, markdown: &str) -> Result<String, String> {
+    match target {
+        CopyTarget::Suggestion => suggestion
+            .map(str::to_string)
+            .ok_or_else(|| "There is no suggested command to copy".to_string()),
+        CopyTarget::Block(number) => {
+            let blocks = markdown::code_blocks(markdown);
+            let block = number.checked_sub(1).and_then(|index| blocks.get(index)).ok_or_else(|| {
+                format!("There is no code block {} to copy; the reply has {}", number, blocks.len())
+            })?;
+            Ok(block.trim_end_matches('\n').to_string())
+        }
+    }
+}
+
+/// The OSC 52 sequence that sets the clipboard, wrapped for tmux or screen
+/// so that they pass it on to the outer terminal.
+fn osc52_sequence(text: &str, tmux: bool, screen: bool) -> String {
+    let sequence = format!("\x1b]52;c;{}\x07", STANDARD.encode(text));
+    if tmux {
+        format!("\x1bPtmux;{}\x1b\\", sequence.replace('\x1b', "\x1b\x1b"))
+    } else if screen {
+        format!("\x1bP{}\x1b\\", sequence)
+    } else {
+        sequence
+    }
+}
+
+/// Writes the OSC 52 sequence to the controlling terminal, which works over
+/// SSH. There is no reply, so this cannot tell whether the terminal honored it.
+fn copy_osc52(text: &str) -> bool {
+    let Ok(mut tty) = OpenOptions::new().write(true).open("/dev/tty") else {
+        return false;
+    };
+    let tmux = std::env::var_os("TMUX").is_some();
+    let screen = std::env::var("TERM").is_ok_and(|term| term.starts_with("screen"));
+    tty.write_all(osc52_sequence(text, tmux, screen).as_bytes()).is_ok() && tty.flush().is_ok()
+}
+
+fn copy_with(program: &str, args: &[&str], text: &str) -> bool {
+    let Ok(mut child) = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+    else {
+        return false;
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        if stdin.write_all(text.as_bytes()).is_err() {
+            return false;
+        }
+    }
+    child.wait().is_ok_and(|status| status.success())
+}
+
+/// Copies `text` with OSC 52, and also with `wl-copy` or `xclip` when a
+/// local display is available, since not every terminal supports OSC 52.
+/// Returns how the text was copied.
+pub fn copy(text: &str) -> Result<String, String> {
+    let mut methods = Vec::new();
+    if copy_osc52(text) {
+        methods.push("OSC 52");
+    }
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() && copy_with("wl-copy", &[], text) {
+        methods.push("wl-copy");
+    } else if std::env::var_os("DISPLAY").is_some() && copy_with("xclip", &["-selection", "clipboard"], text) {
+        methods.push("xclip");
+    }
+
+    if methods.is_empty() {
+        Err("No terminal or clipboard tool available; install wl-copy or xclip".to_string())
+    } else {
+        Ok(methods.join(" and "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_osc52_sequence() {
+        assert_eq!(osc52_sequence("hi", false, false), "\x1b]52;c;aGk=\x07");
+        assert_eq!(osc52_sequence("hi", true, false), "\x1bPtmux;\x1b\x1b]52;c;aGk=\x07\x1b\\");
+        assert_eq!(osc52_sequence("hi", false, true), "\x1bP\x1b]52;c;aGk=\x07\x1b\\");
+    }
+
+    #[test]
+    fn test_select() {
+        let markdown = "Try:\n\n```bash\nls\n```\n\n```bash\npwd\n```";
+        assert_eq!(select(CopyTarget::Suggestion, Some("git status"), markdown).unwrap(), "git status");
+        assert!(select(CopyTarget::Suggestion, None, markdown).is_err());
+        assert_eq!(select(CopyTarget::Block(2), None, markdown).unwrap(), "pwd");
+        assert!(select(CopyTarget::Block(3), None, markdown).is_err());
+        assert!(select(CopyTarget::Block(0), None, markdown).is_err());
+    }
+}
@@ -1,3 +1,8 @@
+use crate::guard;
+use crate::pager;
+use crate::theme;
+use crate::validate;
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::PathBuf;
 use serde::{Deserialize, Serialize};
@@ -5,6 +10,7 @@ use dialoguer::{Select, theme::ColorfulTheme};
 use console::style;
 
 #[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct Config {
     pub default_model: String,
     pub response_length: String,
@@ -14,6 +20,22 @@ pub struct Config {
     pub auto_save_history: bool,
     pub default_shell: String,
     pub api_timeout: u64,
+    pub edit_protocol: String,
+    pub whole_file_max_lines: usize,
+    pub git_dirty_policy: String,
+    pub git_offer_commit: bool,
+    pub max_file_bytes: u64,
+    pub max_context_bytes: u64,
+    pub check_commands: BTreeMap<String, String>,
+    pub max_repair_attempts: u32,
+    pub notebook_clear_outputs: bool,
+    pub protected_paths: Vec<String>,
+    pub restrict_to_git_root: bool,
+    pub batch_concurrency: usize,
+    pub wrap_width: usize,
+    pub theme: String,
+    pub structured_analysis: bool,
+    pub pager: String,
 }
 
 impl Default for Config {
@@ -27,6 +49,22 @@ impl Default for Config {
             auto_save_history: true,
             default_shell: "bash".to_string(),
             api_timeout: 30,
+            edit_protocol: "auto".to_string(),
+            whole_file_max_lines: 200,
+            git_dirty_policy: "warn".to_string(),
+            git_offer_commit: true,
+            max_file_bytes: 256 * 1024,
+            max_context_bytes: 1024 * 1024,
+            check_commands: validate::default_check_commands(),
+            max_repair_attempts: 2,
+            notebook_clear_outputs: true,
+            protected_paths: guard::default_protected_paths(),
+            restrict_to_git_root: true,
+            batch_concurrency: 4,
+            wrap_width: 0,
+            theme: theme::AUTO.to_string(),
+            structured_analysis: true,
+            pager: String::new(),
         }
     }
 }
@@ -109,13 +147,13 @@ impl ConfigManager {
     }
 
     pub fn interactive_config_menu(&self) -> Result<Config, String> {
-        println!("\\n{}", style("🔧 Configuration Menu").bold().cyan());
+        println!("\n{}", style("🔧 Configuration Menu").bold().cyan());
         println!("{}", style("Configure your huh settings:").dim());
         println!();
 
         let current_config = self.load_config()?;
         
-        let options = vec![
+        let options = [
             "Change Default Model",
             "Change Response Length",
             "Change Temperature",
@@ -124,6 +162,7 @@ impl ConfigManager {
             "Toggle Auto-Save History",
             "Change Default Shell",
             "Change API Timeout",
+            "Change Syntax Theme",
             "Show Current Configuration",
             "Exit"
         ];
@@ -143,7 +182,8 @@ impl ConfigManager {
             5 => self.toggle_auto_save(),
             6 => self.change_shell(),
             7 => self.change_timeout(),
-            8 => {
+            8 => self.change_theme(),
+            9 => {
                 println!();
                 println!("{}", style("Current Configuration:").bold().cyan());
                 println!("  Default model: {}", style(&current_config.default_model).cyan());
@@ -154,10 +194,29 @@ impl ConfigManager {
                 println!("  Auto-save history: {}", style(&current_config.auto_save_history.to_string()).cyan());
                 println!("  Default shell: {}", style(&current_config.default_shell).cyan());
                 println!("  API timeout: {} seconds", style(&current_config.api_timeout.to_string()).cyan());
+                println!("  Edit protocol: {}", style(&current_config.edit_protocol).cyan());
+                println!("  Whole-file max lines: {}", style(&current_config.whole_file_max_lines.to_string()).cyan());
+                println!("  Git dirty policy: {}", style(&current_config.git_dirty_policy).cyan());
+                println!("  Offer git commit: {}", style(&current_config.git_offer_commit.to_string()).cyan());
+                println!("  Max file size: {} bytes", style(&current_config.max_file_bytes.to_string()).cyan());
+                println!("  Max context size: {} bytes", style(&current_config.max_context_bytes.to_string()).cyan());
+                println!("  Check commands:");
+                for (extension, command) in &current_config.check_commands {
+                    println!("    .{}: {}", extension, style(command).cyan());
+                }
+                println!("  Max repair attempts: {}", style(&current_config.max_repair_attempts.to_string()).cyan());
+                println!("  Clear stale notebook outputs: {}", style(&current_config.notebook_clear_outputs.to_string()).cyan());
+                println!("  Protected paths: {}", style(&current_config.protected_paths.join(", ")).cyan());
+                println!("  Restrict writes to git root: {}", style(&current_config.restrict_to_git_root.to_string()).cyan());
+                println!("  Batch concurrency: {}", style(&current_config.batch_concurrency.to_string()).cyan());
+                println!("  Wrap width: {}", style(&wrap_width_label(current_config.wrap_width)).cyan());
+                println!("  Theme: {}", style(&current_config.theme).cyan());
+                println!("  Structured analysis: {}", style(&current_config.structured_analysis.to_string()).cyan());
+                println!("  Pager: {}", style(pager_label(&current_config.pager)).cyan());
                 println!();
                 Ok(current_config)
             }
-            9 => {
+            10 => {
                 println!("Configuration unchanged.");
                 Ok(current_config)
             }
@@ -166,7 +225,7 @@ impl ConfigManager {
     }
 
     pub fn change_model(&self) -> Result<Config, String> {
-        println!("\\n{}", style("🔧 Change Default Model").bold().cyan());
+        println!("\n{}", style("🔧 Change Default Model").bold().cyan());
         println!("{}", style("Select your new default Gemini model:").dim());
         println!();
 
@@ -211,12 +270,12 @@ impl ConfigManager {
     }
 
     pub fn change_response_length(&self) -> Result<Config, String> {
-        println!("\\n{}", style("🔧 Change Response Length").bold().cyan());
+        println!("\n{}", style("🔧 Change Response Length").bold().cyan());
         println!("{}", style("Select your preferred response length:").dim());
         println!();
 
         let current_config = self.load_config()?;
-        let response_options = vec![
+        let response_options = [
             ("brief", "Brief - Concise, essential information only"),
             ("balanced", "Balanced - Moderate detail with key information"),
             ("detailed", "Detailed - Comprehensive explanations with context"),
@@ -293,7 +352,7 @@ impl ConfigManager {
     }
 
     pub fn run_first_time_setup(&self) -> Result<Config, String> {
-        println!("\\n{}", style("🚀 Welcome to huh!").bold().cyan());
+        println!("\n{}", style("🚀 Welcome to huh!").bold().cyan());
         println!("{}", style("Let's set up your default Gemini model.").dim());
         println!();
 
@@ -317,20 +376,22 @@ impl ConfigManager {
         println!("{}", style("You can change this anytime with the --model flag.").dim());
         println!();
 
-        let mut config = Config::default();
-        config.default_model = selected_model;
+        let config = Config {
+            default_model: selected_model,
+            ..Config::default()
+        };
 
         self.save_config(&config)?;
         Ok(config)
     }
 
     pub fn change_temperature(&self) -> Result<Config, String> {
-        println!("\\n{}", style("🔧 Change Temperature").bold().cyan());
+        println!("\n{}", style("🔧 Change Temperature").bold().cyan());
         println!("{}", style("Temperature controls creativity (0.0 = focused, 1.0 = creative):").dim());
         println!();
 
         let current_config = self.load_config()?;
-        let temp_options = vec![
+        let temp_options = [
             (0.0, "0.0 - Very focused and deterministic"),
             (0.3, "0.3 - Slightly focused"),
             (0.5, "0.5 - Balanced"),
@@ -376,12 +437,12 @@ impl ConfigManager {
     }
 
     pub fn change_max_tokens(&self) -> Result<Config, String> {
-        println!("\\n{}", style("🔧 Change Max Output Tokens").bold().cyan());
+        println!("\n{}", style("🔧 Change Max Output Tokens").bold().cyan());
         println!("{}", style("Maximum number of tokens in AI responses:").dim());
         println!();
 
         let current_config = self.load_config()?;
-        let token_options = vec![
+        let token_options = [
             (1024, "1024 - Short responses"),
             (2048, "2048 - Medium responses"),
             (4096, "4096 - Long responses"),
@@ -426,7 +487,7 @@ impl ConfigManager {
     }
 
     pub fn toggle_thinking(&self) -> Result<Config, String> {
-        println!("\\n{}", style("🔧 Toggle Thinking Process Display").bold().cyan());
+        println!("\n{}", style("🔧 Toggle Thinking Process Display").bold().cyan());
         println!("{}", style("Show AI reasoning and thought process:").dim());
         println!();
 
@@ -448,7 +509,7 @@ impl ConfigManager {
     }
 
     pub fn toggle_auto_save(&self) -> Result<Config, String> {
-        println!("\\n{}", style("🔧 Toggle Auto-Save History").bold().cyan());
+        println!("\n{}", style("🔧 Toggle Auto-Save History").bold().cyan());
         println!("{}", style("Automatically save conversation history:").dim());
         println!();
 
@@ -470,12 +531,12 @@ impl ConfigManager {
     }
 
     pub fn change_shell(&self) -> Result<Config, String> {
-        println!("\\n{}", style("🔧 Change Default Shell").bold().cyan());
+        println!("\n{}", style("🔧 Change Default Shell").bold().cyan());
         println!("{}", style("Default shell for command execution:").dim());
         println!();
 
         let current_config = self.load_config()?;
-        let shell_options = vec![
+        let shell_options = [
             ("bash", "Bash - Most common Unix shell"),
             ("zsh", "Zsh - Feature-rich shell with plugins"),
             ("fish", "Fish - User-friendly shell with syntax highlighting"),
@@ -521,12 +582,12 @@ impl ConfigManager {
     }
 
     pub fn change_timeout(&self) -> Result<Config, String> {
-        println!("\\n{}", style("🔧 Change API Timeout").bold().cyan());
+        println!("\n{}", style("🔧 Change API Timeout").bold().cyan());
         println!("{}", style("Timeout for API requests in seconds:").dim());
         println!();
 
         let current_config = self.load_config()?;
-        let timeout_options = vec![
+        let timeout_options = [
             (10, "10 seconds - Quick timeout"),
             (20, "20 seconds - Short timeout"),
             (30, "30 seconds - Standard timeout (recommended)"),
@@ -570,6 +631,63 @@ impl ConfigManager {
         self.save_config(&config)?;
         Ok(config)
     }
+
+    pub fn change_theme(&self) -> Result<Config, String> {
+        println!("\n{}", style("🔧 Change Syntax Theme").bold().cyan());
+        println!("{}", style("Theme for highlighting code blocks:").dim());
+        if let Some(dir) = theme::themes_dir() {
+            println!("{}", style(format!("Custom .tmTheme files are loaded from {}", dir.display())).dim());
+        }
+        println!();
+
+        let current_config = self.load_config()?;
+        let mut theme_options = vec![theme::AUTO.to_string()];
+        theme_options.extend(theme::available_themes());
+
+        let option_names: Vec<String> = theme_options
+            .iter()
+            .map(|name| {
+                if name == theme::AUTO {
+                    "auto - Light or dark to match the terminal background".to_string()
+                } else {
+                    name.clone()
+                }
+            })
+            .collect();
+        let current_index = theme_options.iter()
+            .position(|name| *name == current_config.theme)
+            .unwrap_or(0);
+
+        let selection = Select::with_theme(&ColorfulTheme::default())
+            .with_prompt("Select syntax theme")
+            .default(current_index)
+            .items(&option_names)
+            .interact()
+            .map_err(|e| format!("Failed to get user selection: {}", e))?;
+
+        let selected_theme = theme_options[selection].clone();
+
+        println!();
+        if selected_theme == current_config.theme {
+            println!("{} No change - keeping: {}",
+                style("ℹ").blue().bold(),
+                style(&selected_theme).cyan().bold()
+            );
+        } else {
+            println!("{} Changed from {} to {}",
+                style("✓").green().bold(),
+                style(&current_config.theme).dim(),
+                style(&selected_theme).cyan().bold()
+            );
+        }
+        println!();
+
+        let mut config = current_config;
+        config.theme = selected_theme;
+
+        self.save_config(&config)?;
+        Ok(config)
+    }
 }
 
 pub struct GeminiModel {
@@ -612,4 +730,22 @@ pub fn get_available_models() -> Vec<GeminiModel> {
             name: "Gemini 2.5 Flash Lite Preview - Optimized for efficiency",
         },
     ]
-}
\ No newline at end of file
+}
+
+/// Describes the `pager` setting for display.
+pub fn pager_label(pager: &str) -> String {
+    if pager.is_empty() {
+        format!("$PAGER or {}", pager::DEFAULT_PAGER)
+    } else {
+        pager.to_string()
+    }
+}
+
+/// Describes the `wrap_width` setting, where 0 follows the terminal.
+pub fn wrap_width_label(wrap_width: usize) -> String {
+    if wrap_width == 0 {
+        "terminal width".to_string()
+    } else {
+        format!("{} columns", wrap_width)
+    }
+}
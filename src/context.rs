@@ -0,0 +1,479 @@
+use crate::notebook;
+use crate::symbols;
+use globset::GlobBuilder;
+use ignore::WalkBuilder;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+// Bytes inspected when deciding whether a file is binary
+const BINARY_SNIFF_LEN: usize = 8000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextFile {
+    pub path: String,
+    pub content: String,
+    /// 1-based inclusive line range when only part of the file is included
+    pub lines: Option<(usize, usize)>,
+}
+
+/// Part of a file selected with `@path:120-180` or `@path#symbol`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selection {
+    Lines(usize, usize),
+    Symbol(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    pub path: String,
+    pub selection: Option<Selection>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ContextLimits {
+    pub max_file_bytes: u64,
+    pub max_total_bytes: u64,
+}
+
+/// Files gathered for a prompt, plus a note for every file that was left out.
+#[derive(Debug, Default)]
+pub struct CollectedContext {
+    pub files: Vec<ContextFile>,
+    pub skipped: Vec<String>,
+}
+
+/// Finds the `@` references in a query. An argument that is a reference as a
+/// whole (which may contain spaces) is taken as one; otherwise each `@word`
+/// is checked. Only references to existing paths and globs count, so words
+/// like `@dataclass` stay plain text. The query keeps the references' text.
+pub fn split_query(args: &[&str]) -> (Vec<String>, String) {
+    let mut references = Vec::new();
+
+    for arg in args {
+        if let Some(reference) = as_reference(arg) {
+            references.push(reference);
+            continue;
+        }
+        references.extend(arg.split_whitespace().filter_map(as_reference));
+    }
+
+    (references, args.join(" "))
+}
+
+/// The `@` references in an edit instruction that name existing files or
+/// directories. Globs and other `@words` stay plain instruction text.
+pub fn path_references(args: &[&str]) -> Vec<String> {
+    split_query(args)
+        .0
+        .into_iter()
+        .filter(|reference| Path::new(&parse_reference(reference).path).exists())
+        .collect()
+}
+
+/// The reference in an `@` token, without surrounding brackets, quotes or
+/// trailing punctuation, if it names an existing path or is a glob.
+fn as_reference(token: &str) -> Option<String> {
+    let token = token.trim().trim_start_matches(['(', '"', '\'', '`']);
+    let reference = token.strip_prefix('@')?;
+    let reference = reference.trim_end_matches(['.', ',', ';', ':', '!', '?', '"', '\'', '`']);
+    let reference = if reference.ends_with(')') && !reference.contains('(') {
+        reference.trim_end_matches(')').trim_end_matches(['.', ',', ';', ':', '!', '?'])
+    } else {
+        reference
+    };
+    if reference.is_empty() {
+        return None;
+    }
+
+    // `?` and `[` also appear in ordinary words, so only `*` and `{a,b}`
+    // mark a glob that does not name an existing path
+    let path = parse_reference(reference).path;
+    let intentional_glob = path.contains('*') || (path.contains('{') && path.contains('}'));
+    if intentional_glob || Path::new(&path).exists() {
+        Some(reference.to_string())
+    } else {
+        None
+    }
+}
+
+/// Splits an `@` reference into its path and an optional line range
+/// (`path:120-180` or `path:42`) or symbol (`path#name`).
+pub fn parse_reference(raw: &str) -> Reference {
+    if let Some((path, symbol)) = raw.rsplit_once('#') {
+        let is_identifier = symbol.chars().next().is_some_and(|c| c.is_alphabetic() || c == '_')
+            && symbol.chars().all(|c| c.is_alphanumeric() || c == '_');
+        if !path.is_empty() && is_identifier {
+            return Reference {
+                path: path.to_string(),
+                selection: Some(Selection::Symbol(symbol.to_string())),
+            };
+        }
+    }
+
+    if let Some((path, range)) = raw.rsplit_once(':') {
+        let bounds = match range.split_once('-') {
+            Some((start, end)) => start.parse::<usize>().ok().zip(end.parse::<usize>().ok()),
+            None => range.parse::<usize>().ok().map(|line| (line, line)),
+        };
+        if let Some((start, end)) = bounds {
+            if !path.is_empty() && start >= 1 && end >= start {
+                return Reference {
+                    path: path.to_string(),
+                    selection: Some(Selection::Lines(start, end)),
+                };
+            }
+        }
+    }
+
+    Reference {
+        path: raw.to_string(),
+        selection: None,
+    }
+}
+
+/// Resolves a selection against the file content to a 0-based line range
+/// `[start, end)`.
+pub fn resolve_selection(content: &str, selection: &Selection, path: &str) -> Result<(usize, usize), String> {
+    match selection {
+        Selection::Lines(start, end) => {
+            let total = content.lines().count();
+            if *start > total {
+                return Err(format!("{} has only {} lines; cannot select line {}", path, total, start));
+            }
+            Ok((start - 1, (*end).min(total)))
+        }
+        Selection::Symbol(name) => symbols::find_symbol(content, name)
+            .ok_or_else(|| format!("Could not find a definition of `{}` in {}", name, path)),
+    }
+}
+
+/// Splits content into the text before, inside and after the 0-based line
+/// range `[start, end)`, keeping line endings intact.
+pub fn split_region(content: &str, start: usize, end: usize) -> (String, String, String) {
+    let lines: Vec<&str> = content.split_inclusive('\n').collect();
+    let end = end.min(lines.len());
+    let start = start.min(end);
+    (lines[..start].concat(), lines[start..end].concat(), lines[end..].concat())
+}
+
+/// Whether a reference is a glob pattern. A path that exists as written is
+/// never treated as one, so `notes[1].txt` can still be referenced.
+pub fn is_glob(reference: &str) -> bool {
+    reference.contains(['*', '?', '[', '{']) && !Path::new(reference).exists()
+}
+
+/// Expands a reference to a sorted list of files. Plain files are returned
+/// as given; directories and globs are walked respecting `.gitignore`.
+pub fn resolve_reference(reference: &str) -> Result<Vec<PathBuf>, String> {
+    let reference = reference.strip_prefix("./").unwrap_or(reference);
+
+    if is_glob(reference) {
+        let matcher = GlobBuilder::new(reference)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| format!("Invalid glob @{}: {}", reference, e))?
+            .compile_matcher();
+        let base = glob_base(reference);
+        let files: Vec<PathBuf> = walk(&base)
+            .into_iter()
+            .filter(|path| matcher.is_match(path.strip_prefix("./").unwrap_or(path)))
+            .collect();
+        if files.is_empty() {
+            return Err(format!("No files match @{}", reference));
+        }
+        return Ok(files);
+    }
+
+    let path = Path::new(reference);
+    if path.is_dir() {
+        Ok(walk(path))
+    } else if path.is_file() {
+        Ok(vec![path.to_path_buf()])
+    } else {
+        Err(format!("Error reading file {}: no such file or directory", reference))
+    }
+}
+
+/// The longest leading part of a glob that contains no wildcards.
+fn glob_base(pattern: &str) -> PathBuf {
+    let mut base = PathBuf::new();
+    for component in pattern.split('/') {
+        if is_glob(component) {
+            break;
+        }
+        base.push(component);
+    }
+    // The last literal component may be a file name prefix rather than a directory
+    if !base.is_dir() {
+        base = base.parent().map(Path::to_path_buf).unwrap_or_default();
+    }
+    if base.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        base
+    }
+}
+
+fn walk(root: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = WalkBuilder::new(root)
+        .build()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_some_and(|t| t.is_file()))
+        .map(|entry| entry.into_path())
+        .collect();
+    files.sort();
+    files
+}
+
+pub fn is_binary(path: &Path) -> bool {
+    let mut buffer = [0u8; BINARY_SNIFF_LEN];
+    let read = match fs::File::open(path).and_then(|mut f| f.read(&mut buffer)) {
+        Ok(read) => read,
+        Err(_) => return false,
+    };
+    buffer[..read].contains(&0)
+}
+
+/// Reads every referenced file within the configured size limits. Binary
+/// files and files over the per-file limit are skipped; once the total
+/// limit is reached the remaining files are skipped as well.
+pub fn collect_context(references: &[String], limits: ContextLimits) -> Result<CollectedContext, String> {
+    let mut seen = BTreeSet::new();
+    let mut collected = CollectedContext::default();
+    let mut total: u64 = 0;
+
+    for reference in references {
+        let parsed = parse_reference(reference);
+        if let Some(selection) = parsed.selection {
+            let path = Path::new(&parsed.path);
+            let display = display_path(path);
+            // The same per-file checks as whole files, before reading it all in
+            let size = fs::metadata(path)
+                .map_err(|e| format!("Error reading file {}: {}", display, e))?
+                .len();
+            if size > limits.max_file_bytes && !notebook::is_notebook(&display) {
+                collected.skipped.push(format!(
+                    "{} ({} bytes, over the {} byte per-file limit)",
+                    display, size, limits.max_file_bytes
+                ));
+                continue;
+            }
+            if is_binary(path) {
+                collected.skipped.push(format!("{} (binary file)", display));
+                continue;
+            }
+            let content = match fs::read_to_string(path) {
+                Ok(content) => notebook::render_for_context(path, content),
+                Err(_) => {
+                    collected.skipped.push(format!("{} (not valid UTF-8)", display));
+                    continue;
+                }
+            };
+            if content.len() as u64 > limits.max_file_bytes {
+                collected.skipped.push(format!(
+                    "{} ({} bytes, over the {} byte per-file limit)",
+                    display,
+                    content.len(),
+                    limits.max_file_bytes
+                ));
+                continue;
+            }
+            let (start, end) = resolve_selection(&content, &selection, &parsed.path)?;
+            let excerpt = content.lines().skip(start).take(end - start).collect::<Vec<&str>>().join("\n");
+
+            if !seen.insert(format!("{}:{}-{}", display, start, end)) {
+                continue;
+            }
+            let size = excerpt.len() as u64;
+            if total + size > limits.max_total_bytes {
+                collected.skipped.push(format!(
+                    "{} (total context limit of {} bytes reached)",
+                    display, limits.max_total_bytes
+                ));
+                continue;
+            }
+            total += size;
+            collected.files.push(ContextFile {
+                path: display,
+                content: excerpt,
+                lines: Some((start + 1, end)),
+            });
+            continue;
+        }
+
+        for path in resolve_reference(reference)? {
+            let display = display_path(&path);
+            if !seen.insert(display.clone()) {
+                continue;
+            }
+
+            // Notebooks are measured after their outputs are stripped
+            let is_notebook = notebook::is_notebook(&display);
+            let size = fs::metadata(&path)
+                .map_err(|e| format!("Error reading file {}: {}", display, e))?
+                .len();
+            if !is_notebook {
+                if let Some(reason) = over_limit(size, total, limits) {
+                    collected.skipped.push(format!("{} ({})", display, reason));
+                    continue;
+                }
+            }
+            if is_binary(&path) {
+                collected.skipped.push(format!("{} (binary file)", display));
+                continue;
+            }
+
+            let content = match fs::read_to_string(&path) {
+                Ok(content) => notebook::render_for_context(&path, content),
+                Err(_) => {
+                    collected.skipped.push(format!("{} (not valid UTF-8)", display));
+                    continue;
+                }
+            };
+            let size = if is_notebook { content.len() as u64 } else { size };
+            if let Some(reason) = over_limit(size, total, limits) {
+                collected.skipped.push(format!("{} ({})", display, reason));
+                continue;
+            }
+
+            total += size;
+            collected.files.push(ContextFile {
+                path: display,
+                content,
+                lines: None,
+            });
+        }
+    }
+
+    Ok(collected)
+}
+
+fn over_limit(size: u64, total: u64, limits: ContextLimits) -> Option<String> {
+    if size > limits.max_file_bytes {
+        Some(format!("{} bytes, over the {} byte per-file limit", size, limits.max_file_bytes))
+    } else if total + size > limits.max_total_bytes {
+        Some(format!("total context limit of {} bytes reached", limits.max_total_bytes))
+    } else {
+        None
+    }
+}
+
+fn display_path(path: &Path) -> String {
+    path.strip_prefix("./").unwrap_or(path).to_string_lossy().to_string()
+}
+
+pub fn format_context(files: &[ContextFile]) -> String {
+    files
+        .iter()
+        .map(|file| match file.lines {
+            Some((start, end)) => format!(
+                "Content from {} (lines {}-{}):\n---\n{}\n---\n",
+                file.path, start, end, file.content
+            ),
+            None => format!("Content from {}:\n---\n{}\n---\n", file.path, file.content),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_split_query_finds_references_anywhere() {
+        let (references, query) = split_query(&["compare", "@Cargo.toml", "with @src/**/*.rs please"]);
+        assert_eq!(references, vec!["Cargo.toml", "src/**/*.rs"]);
+        assert_eq!(query, "compare @Cargo.toml with @src/**/*.rs please");
+    }
+
+    #[test]
+    fn test_split_query_strips_punctuation() {
+        let (references, query) = split_query(&["How does @src/main.rs use @src/config.rs?"]);
+        assert_eq!(references, vec!["src/main.rs", "src/config.rs"]);
+        assert_eq!(query, "How does @src/main.rs use @src/config.rs?");
+        let (references, _) = split_query(&["see (@src/main.rs:1-5), then @Cargo.toml."]);
+        assert_eq!(references, vec!["src/main.rs:1-5", "Cargo.toml"]);
+    }
+
+    #[test]
+    fn test_split_query_leaves_unknown_words() {
+        let (references, query) = split_query(&["why use @dataclass or @Override here", "mail me@example.com @", "@what?"]);
+        assert!(references.is_empty());
+        assert_eq!(query, "why use @dataclass or @Override here mail me@example.com @ @what?");
+    }
+
+    #[test]
+    fn test_path_references_skip_globs() {
+        assert_eq!(
+            path_references(&["use", "@Cargo.toml:1-2", "and", "@src/*.rs", "for", "@dataclass"]),
+            vec!["Cargo.toml:1-2"]
+        );
+    }
+
+    #[test]
+    fn test_split_query_whole_argument_with_spaces() {
+        let dir = TempDir::new("context");
+        let path = dir.join("my notes.txt");
+        fs::write(&path, "hi").unwrap();
+        let argument = format!("@{}", path.display());
+        let (references, _) = split_query(&[&argument, "summarise"]);
+        assert_eq!(references, vec![path.display().to_string()]);
+    }
+
+    #[test]
+    fn test_parse_reference() {
+        assert_eq!(parse_reference("src/main.rs").selection, None);
+        assert_eq!(
+            parse_reference("src/main.rs:120-180"),
+            Reference { path: "src/main.rs".to_string(), selection: Some(Selection::Lines(120, 180)) }
+        );
+        assert_eq!(parse_reference("a.py:7").selection, Some(Selection::Lines(7, 7)));
+        assert_eq!(
+            parse_reference("src/gemini.rs#wrap_text"),
+            Reference { path: "src/gemini.rs".to_string(), selection: Some(Selection::Symbol("wrap_text".to_string())) }
+        );
+        // Not a valid range or symbol, so the whole string is the path
+        assert_eq!(parse_reference("notes:draft").path, "notes:draft");
+        assert_eq!(parse_reference("a.rs:0-3").selection, None);
+    }
+
+    #[test]
+    fn test_resolve_selection() {
+        let content = "a\nb\nc\nd\n";
+        assert_eq!(resolve_selection(content, &Selection::Lines(2, 3), "x"), Ok((1, 3)));
+        assert_eq!(resolve_selection(content, &Selection::Lines(3, 99), "x"), Ok((2, 4)));
+        assert!(resolve_selection(content, &Selection::Lines(9, 10), "x").is_err());
+    }
+
+    #[test]
+    fn test_split_region() {
+        let (before, region, after) = split_region("a\nb\nc\nd", 1, 3);
+        assert_eq!((before.as_str(), region.as_str(), after.as_str()), ("a\n", "b\nc\n", "d"));
+    }
+
+    #[test]
+    fn test_glob_base() {
+        assert_eq!(glob_base("src/**/*.rs"), PathBuf::from("src"));
+        assert_eq!(glob_base("*.toml"), PathBuf::from("."));
+    }
+
+    #[test]
+    fn test_resolve_glob_and_limits() {
+        let files = resolve_reference("src/*.rs").unwrap();
+        assert!(files.iter().any(|p| p.ends_with("context.rs")));
+        assert!(files.iter().all(|p| p.extension().unwrap() == "rs"));
+
+        let limits = ContextLimits { max_file_bytes: 1, max_total_bytes: 1 };
+        let collected = collect_context(&["Cargo.toml".to_string()], limits).unwrap();
+        assert!(collected.files.is_empty());
+        assert_eq!(collected.skipped.len(), 1);
+
+        // Line ranges and symbols are checked against the same limits
+        let collected = collect_context(&["Cargo.toml:1-2".to_string()], limits).unwrap();
+        assert!(collected.files.is_empty());
+        assert_eq!(collected.skipped.len(), 1);
+    }
+}
@@ -0,0 +1,509 @@
+use strsim::normalized_levenshtein;
+
+const SEARCH_MARKER: &str = "<<<<<<< SEARCH";
+const DIVIDER_MARKER: &str = "=======";
+const REPLACE_MARKER: &str = ">>>>>>> REPLACE";
+
+// Minimum average line similarity for a fuzzy match to be accepted
+const FUZZY_THRESHOLD: f64 = 0.85;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditProtocol {
+    WholeFile,
+    SearchReplace,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EditBlock {
+    pub search: String,
+    pub replace: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EditFailure {
+    pub index: usize,
+    pub reason: String,
+    pub search: String,
+}
+
+impl EditFailure {
+    pub fn describe(&self) -> String {
+        let preview = self.search.lines().next().unwrap_or("").trim();
+        format!("Block {}: {} (starting with `{}`)", self.index + 1, self.reason, preview)
+    }
+}
+
+/// Picks the protocol for an edit. `protocol` is the configured value
+/// ("auto", "whole" or "search_replace"); new files and files with at most
+/// `whole_file_max_lines` lines fall back to whole-file mode under "auto".
+pub fn choose_protocol(protocol: &str, original: Option<&str>, whole_file_max_lines: usize) -> EditProtocol {
+    let original = match original {
+        Some(content) if !content.trim().is_empty() => content,
+        _ => return EditProtocol::WholeFile,
+    };
+
+    match protocol {
+        "whole" => EditProtocol::WholeFile,
+        "search_replace" => EditProtocol::SearchReplace,
+        _ => {
+            if original.lines().count() <= whole_file_max_lines {
+                EditProtocol::WholeFile
+            } else {
+                EditProtocol::SearchReplace
+            }
+        }
+    }
+}
+
+pub fn protocol_instructions() -> String {
+    format!(
+        "Do NOT output the whole file. Respond only with one or more edit blocks in exactly this format:\n\n\
+        {}\n\
+        <exact lines copied from the current file>\n\
+        {}\n\
+        <lines that should replace them>\n\
+        {}\n\n\
+        Rules:\n\
+        - The SEARCH section must match the current file exactly, including indentation and comments.\n\
+        - Include just enough surrounding lines to make each SEARCH section unique in the file.\n\
+        - Use several small blocks rather than one large block when changes are far apart.\n\
+        - To delete code, leave the replacement section empty.\n\
+        - Do not add explanations outside the blocks.",
+        SEARCH_MARKER, DIVIDER_MARKER, REPLACE_MARKER
+    )
+}
+
+/// Parses either search/replace blocks or a unified diff from a model reply.
+pub fn parse_edit_response(text: &str) -> Result<Vec<EditBlock>, String> {
+    let text = text.replace("\r\n", "\n");
+
+    let blocks = if text.contains(SEARCH_MARKER) {
+        parse_search_replace_blocks(&text)?
+    } else if text.lines().any(|line| line.starts_with("@@")) {
+        parse_unified_diff(&text)?
+    } else {
+        return Err("Response did not contain any search/replace blocks or diff hunks".to_string());
+    };
+
+    if blocks.is_empty() {
+        return Err("Response did not contain any search/replace blocks or diff hunks".to_string());
+    }
+
+    Ok(blocks)
+}
+
+fn parse_search_replace_blocks(text: &str) -> Result<Vec<EditBlock>, String> {
+    enum State {
+        Outside,
+        Search,
+        Replace,
+    }
+
+    let mut blocks = Vec::new();
+    let mut state = State::Outside;
+    let mut search = Vec::new();
+    let mut replace = Vec::new();
+
+    for line in text.lines() {
+        let marker = line.trim_end();
+        match state {
+            State::Outside => {
+                if marker.starts_with(SEARCH_MARKER) {
+                    state = State::Search;
+                }
+            }
+            State::Search => {
+                if marker == DIVIDER_MARKER {
+                    state = State::Replace;
+                } else {
+                    search.push(line);
+                }
+            }
+            State::Replace => {
+                if marker.starts_with(REPLACE_MARKER) {
+                    blocks.push(EditBlock {
+                        search: join_lines(&search),
+                        replace: join_lines(&replace),
+                    });
+                    search.clear();
+                    replace.clear();
+                    state = State::Outside;
+                } else {
+                    replace.push(line);
+                }
+            }
+        }
+    }
+
+    if !matches!(state, State::Outside) {
+        return Err(format!(
+            "Edit block {} is incomplete (missing {} marker)",
+            blocks.len() + 1,
+            if matches!(state, State::Search) { DIVIDER_MARKER } else { REPLACE_MARKER }
+        ));
+    }
+
+    Ok(blocks)
+}
+
+fn parse_unified_diff(text: &str) -> Result<Vec<EditBlock>, String> {
+    let mut blocks = Vec::new();
+    let mut in_hunk = false;
+    let mut search = Vec::new();
+    let mut replace = Vec::new();
+
+    let mut flush = |search: &mut Vec<&str>, replace: &mut Vec<&str>| {
+        if !search.is_empty() || !replace.is_empty() {
+            blocks.push(EditBlock {
+                search: join_lines(search),
+                replace: join_lines(replace),
+            });
+        }
+        search.clear();
+        replace.clear();
+    };
+
+    let mut lines = text.lines().peekable();
+    while let Some(line) = lines.next() {
+        if line.starts_with("@@") {
+            flush(&mut search, &mut replace);
+            in_hunk = true;
+            continue;
+        }
+        if !in_hunk {
+            continue;
+        }
+
+        // Inside a hunk `--- ` is usually a removed line starting with `-- `;
+        // it only starts the next file when a `+++ ` header follows
+        let next_file = line.starts_with("--- ") && lines.peek().is_some_and(|next| next.starts_with("+++ "));
+        if next_file || line.starts_with("diff ") {
+            flush(&mut search, &mut replace);
+            in_hunk = false;
+        } else if let Some(rest) = line.strip_prefix('-') {
+            search.push(rest);
+        } else if let Some(rest) = line.strip_prefix('+') {
+            replace.push(rest);
+        } else if let Some(rest) = line.strip_prefix(' ') {
+            search.push(rest);
+            replace.push(rest);
+        } else if line.is_empty() {
+            // Blank context lines frequently lose their leading space
+            search.push("");
+            replace.push("");
+        } else if line.starts_with('\\') {
+            // "\ No newline at end of file"
+        } else {
+            flush(&mut search, &mut replace);
+            in_hunk = false;
+        }
+    }
+    flush(&mut search, &mut replace);
+
+    Ok(blocks)
+}
+
+fn join_lines(lines: &[&str]) -> String {
+    if lines.is_empty() {
+        String::new()
+    } else {
+        format!("{}\n", lines.join("\n"))
+    }
+}
+
+/// Applies every block in order. If any block cannot be anchored the
+/// original content is left untouched and all failures are returned.
+pub fn apply_edits(original: &str, blocks: &[EditBlock]) -> Result<String, Vec<EditFailure>> {
+    let mut content = original.to_string();
+    let mut failures = Vec::new();
+
+    for (index, block) in blocks.iter().enumerate() {
+        match apply_block(&content, block) {
+            Ok(updated) => content = updated,
+            Err(reason) => failures.push(EditFailure {
+                index,
+                reason,
+                search: block.search.clone(),
+            }),
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(content)
+    } else {
+        Err(failures)
+    }
+}
+
+fn apply_block(content: &str, block: &EditBlock) -> Result<String, String> {
+    if block.search.trim().is_empty() {
+        // An empty search section appends to the end of the file
+        let mut updated = content.to_string();
+        if !updated.is_empty() && !updated.ends_with('\n') {
+            updated.push('\n');
+        }
+        updated.push_str(&block.replace);
+        return Ok(updated);
+    }
+
+    let lines: Vec<&str> = content.split_inclusive('\n').collect();
+    let search_lines: Vec<&str> = block.search.lines().collect();
+
+    // 1. Exact line match, 2. whitespace-insensitive line match, 3. similarity match
+    let (start, len, replacement) = match find_exact_match(&lines, &search_lines)? {
+        Some((start, len)) => (start, len, block.replace.clone()),
+        None => {
+            let (start, len) = match find_whitespace_match(&lines, &search_lines)? {
+                Some(found) => found,
+                None => find_fuzzy_match(&lines, &search_lines)?
+                    .ok_or_else(|| "search text not found in file".to_string())?,
+            };
+            (start, len, reindent(&block.replace, search_lines.first().copied(), lines[start]))
+        }
+    };
+
+    let mut updated = String::new();
+    for line in &lines[..start] {
+        updated.push_str(line);
+    }
+    updated.push_str(&replacement);
+    let replaced_last = lines[start + len - 1];
+    if !replaced_last.ends_with('\n') && updated.ends_with('\n') && start + len == lines.len() {
+        updated.pop();
+    }
+    for line in &lines[start + len..] {
+        updated.push_str(line);
+    }
+
+    Ok(updated)
+}
+
+/// Finds the search block as whole lines, so that it never matches part
+/// of a longer line.
+fn find_exact_match(lines: &[&str], search_lines: &[&str]) -> Result<Option<(usize, usize)>, String> {
+    if search_lines.len() > lines.len() {
+        return Ok(None);
+    }
+
+    let matches: Vec<usize> = (0..=lines.len() - search_lines.len())
+        .filter(|&start| {
+            search_lines
+                .iter()
+                .zip(&lines[start..start + search_lines.len()])
+                .all(|(search, line)| *search == line.trim_end_matches('\n').trim_end_matches('\r'))
+        })
+        .collect();
+
+    match matches.len() {
+        0 => Ok(None),
+        1 => Ok(Some((matches[0], search_lines.len()))),
+        n => Err(format!("search text matches {} locations; add more context", n)),
+    }
+}
+
+fn find_whitespace_match(lines: &[&str], search_lines: &[&str]) -> Result<Option<(usize, usize)>, String> {
+    if search_lines.len() > lines.len() {
+        return Ok(None);
+    }
+
+    let matches: Vec<usize> = (0..=lines.len() - search_lines.len())
+        .filter(|&start| {
+            search_lines
+                .iter()
+                .zip(&lines[start..start + search_lines.len()])
+                .all(|(search, line)| search.trim() == line.trim())
+        })
+        .collect();
+
+    match matches.len() {
+        0 => Ok(None),
+        1 => Ok(Some((matches[0], search_lines.len()))),
+        n => Err(format!("search text matches {} locations when ignoring whitespace; add more context", n)),
+    }
+}
+
+fn find_fuzzy_match(lines: &[&str], search_lines: &[&str]) -> Result<Option<(usize, usize)>, String> {
+    if search_lines.len() > lines.len() {
+        return Ok(None);
+    }
+
+    let mut best: Option<(usize, f64)> = None;
+    let mut runner_up = 0.0;
+
+    for start in 0..=lines.len() - search_lines.len() {
+        let window = &lines[start..start + search_lines.len()];
+        let score = search_lines
+            .iter()
+            .zip(window)
+            .map(|(search, line)| normalized_levenshtein(search.trim(), line.trim()))
+            .sum::<f64>()
+            / search_lines.len() as f64;
+
+        match best {
+            Some((_, best_score)) if score <= best_score => {
+                if score > runner_up {
+                    runner_up = score;
+                }
+            }
+            Some((_, best_score)) => {
+                runner_up = best_score;
+                best = Some((start, score));
+            }
+            None => best = Some((start, score)),
+        }
+    }
+
+    match best {
+        Some((start, score)) if score >= FUZZY_THRESHOLD => {
+            if (score - runner_up).abs() < f64::EPSILON {
+                Err("search text is ambiguous; several locations match equally well".to_string())
+            } else {
+                Ok(Some((start, search_lines.len())))
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+fn leading_whitespace(line: &str) -> &str {
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/// Shifts the replacement so that it lines up with the indentation found in
+/// the file when the model got the indentation of the SEARCH section wrong.
+fn reindent(replace: &str, search_first: Option<&str>, file_first: &str) -> String {
+    let search_indent = search_first.map(leading_whitespace).unwrap_or("");
+    let file_indent = leading_whitespace(file_first.trim_end_matches(['\r', '\n']));
+
+    if search_indent == file_indent {
+        return replace.to_string();
+    }
+
+    replace
+        .split_inclusive('\n')
+        .map(|line| {
+            if line.trim().is_empty() {
+                line.to_string()
+            } else if let Some(extra) = file_indent.strip_prefix(search_indent) {
+                format!("{}{}", extra, line)
+            } else if let Some(extra) = search_indent.strip_prefix(file_indent) {
+                line.strip_prefix(extra).unwrap_or(line).to_string()
+            } else {
+                line.to_string()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_search_replace_blocks() {
+        let reply = "Here you go:\n```\n<<<<<<< SEARCH\nfn a() {}\n=======\nfn a() { b() }\n>>>>>>> REPLACE\n```\n";
+        let blocks = parse_edit_response(reply).unwrap();
+        assert_eq!(blocks, vec![EditBlock {
+            search: "fn a() {}\n".to_string(),
+            replace: "fn a() { b() }\n".to_string(),
+        }]);
+    }
+
+    #[test]
+    fn test_parse_incomplete_block_is_error() {
+        let reply = "<<<<<<< SEARCH\nfn a() {}\n=======\nfn b() {}\n";
+        assert!(parse_edit_response(reply).is_err());
+    }
+
+    #[test]
+    fn test_parse_unified_diff() {
+        let reply = "--- a/x.txt\n+++ b/x.txt\n@@ -1,3 +1,3 @@\n one\n-two\n+TWO\n three\n";
+        let blocks = parse_edit_response(reply).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].search, "one\ntwo\nthree\n");
+        assert_eq!(blocks[0].replace, "one\nTWO\nthree\n");
+    }
+
+    #[test]
+    fn test_parse_unified_diff_removed_comment_line() {
+        let reply = "--- a/q.sql\n+++ b/q.sql\n@@ -1,3 +1,2 @@\n SELECT 1;\n--- old comment\n SELECT 2;\n\
+            --- a/r.sql\n+++ b/r.sql\n@@ -1 +1 @@\n-x\n+y\n";
+        let blocks = parse_edit_response(reply).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].search, "SELECT 1;\n-- old comment\nSELECT 2;\n");
+        assert_eq!(blocks[0].replace, "SELECT 1;\nSELECT 2;\n");
+        assert_eq!(blocks[1].search, "x\n");
+    }
+
+    #[test]
+    fn test_apply_exact() {
+        let original = "one\ntwo\nthree\n";
+        let blocks = vec![EditBlock { search: "two\n".to_string(), replace: "2\n".to_string() }];
+        assert_eq!(apply_edits(original, &blocks).unwrap(), "one\n2\nthree\n");
+    }
+
+    #[test]
+    fn test_apply_exact_matches_whole_lines() {
+        let original = "let max = 1;\nx = 1\n";
+        let blocks = vec![EditBlock { search: "x = 1\n".to_string(), replace: "y = 9\n".to_string() }];
+        assert_eq!(apply_edits(original, &blocks).unwrap(), "let max = 1;\ny = 9\n");
+
+        let blocks = vec![EditBlock { search: "x = 1\n".to_string(), replace: "y = 9\n".to_string() }];
+        assert!(apply_edits("let max = 1;\n", &blocks).is_err());
+    }
+
+    #[test]
+    fn test_apply_ambiguous_exact_fails() {
+        let original = "x\nx\n";
+        let blocks = vec![EditBlock { search: "x\n".to_string(), replace: "y\n".to_string() }];
+        assert!(apply_edits(original, &blocks).is_err());
+    }
+
+    #[test]
+    fn test_apply_whitespace_insensitive_reindents() {
+        let original = "fn main() {\n    let a = 1;\n    let b = 2;\n}\n";
+        let blocks = vec![EditBlock {
+            search: "let a = 1;\nlet b = 2;\n".to_string(),
+            replace: "let a = 10;\nlet b = 20;\n".to_string(),
+        }];
+        assert_eq!(
+            apply_edits(original, &blocks).unwrap(),
+            "fn main() {\n    let a = 10;\n    let b = 20;\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_apply_fuzzy() {
+        let original = "def greet(name):\n    print('hello', name)\n    return None\n";
+        let blocks = vec![EditBlock {
+            search: "def greet(name):\n    print(\"hello\", name)\n".to_string(),
+            replace: "def greet(name):\n    print(f'hi {name}')\n".to_string(),
+        }];
+        assert_eq!(
+            apply_edits(original, &blocks).unwrap(),
+            "def greet(name):\n    print(f'hi {name}')\n    return None\n"
+        );
+    }
+
+    #[test]
+    fn test_apply_reports_all_failures_and_keeps_original() {
+        let original = "alpha\nbeta\n";
+        let blocks = vec![
+            EditBlock { search: "alpha\n".to_string(), replace: "ALPHA\n".to_string() },
+            EditBlock { search: "completely different text\n".to_string(), replace: "x\n".to_string() },
+        ];
+        let failures = apply_edits(original, &blocks).unwrap_err();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].index, 1);
+    }
+
+    #[test]
+    fn test_choose_protocol() {
+        let long_file = "line\n".repeat(500);
+        assert_eq!(choose_protocol("auto", None, 200), EditProtocol::WholeFile);
+        assert_eq!(choose_protocol("auto", Some("short\n"), 200), EditProtocol::WholeFile);
+        assert_eq!(choose_protocol("auto", Some(&long_file), 200), EditProtocol::SearchReplace);
+        assert_eq!(choose_protocol("whole", Some(&long_file), 200), EditProtocol::WholeFile);
+        assert_eq!(choose_protocol("search_replace", Some("short\n"), 200), EditProtocol::SearchReplace);
+    }
+}
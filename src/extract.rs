@@ -0,0 +1,260 @@
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FencedBlock {
+    lang: String,
+    content: String,
+}
+
+struct Fence<'a> {
+    ch: char,
+    len: usize,
+    info: &'a str,
+}
+
+/// Pulls the file content out of a model reply. Handles replies wrapped in
+/// a (possibly tagged) code fence, prose before or after the fence, fences
+/// nested inside the file, several fenced blocks and CRLF line endings.
+/// `file_path` is used to pick the right block when there are several;
+/// when none is in its language the reply is kept as it is.
+pub fn extract_file_content(reply: &str, file_path: &str) -> String {
+    let text = reply.replace("\r\n", "\n");
+    let lines: Vec<&str> = text.split('\n').collect();
+    let extension = Path::new(file_path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+
+    if let Some(block) = whole_reply_block(&lines) {
+        return normalize(&block.content);
+    }
+
+    let blocks = fenced_blocks(&lines);
+    let matching: Vec<&FencedBlock> = blocks
+        .iter()
+        .filter(|b| language_matches(&b.lang, &extension))
+        .collect();
+
+    // Documents of any type may contain code samples, so a block in another
+    // language is only taken when it is the reply's single block. Markdown
+    // files legitimately contain fences, so only a block explicitly tagged
+    // as markdown is taken to be the file.
+    let is_markdown = matches!(extension.as_str(), "md" | "markdown" | "mdx");
+    let candidates: Vec<&FencedBlock> = if !matching.is_empty() {
+        matching
+    } else if blocks.len() == 1 && !is_markdown {
+        blocks.iter().collect()
+    } else {
+        return normalize(&text);
+    };
+
+    let best = candidates
+        .into_iter()
+        .max_by_key(|b| b.content.len())
+        .expect("candidates is never empty");
+    normalize(&best.content)
+}
+
+/// Removes a code fence that wraps the whole reply, for output that is not
+/// a file of a known type. Fences inside the text are kept, along with the
+/// prose around them.
+pub fn unwrap_reply(reply: &str) -> String {
+    let text = reply.replace("\r\n", "\n");
+    let lines: Vec<&str> = text.split('\n').collect();
+    match whole_reply_block(&lines) {
+        Some(block) => normalize(&block.content),
+        None => normalize(&text),
+    }
+}
+
+fn parse_fence(line: &str) -> Option<Fence<'_>> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent > 3 {
+        return None;
+    }
+    let rest = &line[indent..];
+    let ch = rest.chars().next()?;
+    if ch != '`' && ch != '~' {
+        return None;
+    }
+    let len = rest.chars().take_while(|&c| c == ch).count();
+    if len < 3 {
+        return None;
+    }
+    let info = rest[len..].trim();
+    if ch == '`' && info.contains('`') {
+        return None;
+    }
+    Some(Fence { ch, len, info })
+}
+
+fn is_closing(line: &str, open: &Fence) -> bool {
+    parse_fence(line).is_some_and(|f| f.ch == open.ch && f.len >= open.len && f.info.is_empty())
+}
+
+/// Returns the block when the reply, ignoring surrounding blank lines, is
+/// exactly one fenced block. Everything between the first and last line is
+/// taken verbatim, so inner fences of any kind are preserved.
+fn whole_reply_block(lines: &[&str]) -> Option<FencedBlock> {
+    let first = lines.iter().position(|l| !l.trim().is_empty())?;
+    let last = lines.iter().rposition(|l| !l.trim().is_empty())?;
+    if last <= first {
+        return None;
+    }
+
+    let open = parse_fence(lines[first])?;
+    if !is_closing(lines[last], &open) {
+        return None;
+    }
+
+    Some(FencedBlock {
+        lang: open.info.to_string(),
+        content: lines[first + 1..last].join("\n"),
+    })
+}
+
+fn fenced_blocks(lines: &[&str]) -> Vec<FencedBlock> {
+    let mut blocks = Vec::new();
+    let mut i = 0;
+
+    while i < lines.len() {
+        let open = match parse_fence(lines[i]) {
+            Some(fence) => fence,
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+
+        // Tagged fences inside the block open nested blocks; bare fences close them
+        let mut depth = 0;
+        let mut close = None;
+        for (j, line) in lines.iter().enumerate().skip(i + 1) {
+            if let Some(fence) = parse_fence(line) {
+                if fence.ch != open.ch || fence.len < open.len {
+                    continue;
+                }
+                if !fence.info.is_empty() {
+                    depth += 1;
+                } else if depth == 0 {
+                    close = Some(j);
+                    break;
+                } else {
+                    depth -= 1;
+                }
+            }
+        }
+
+        let end = close.unwrap_or(lines.len());
+        blocks.push(FencedBlock {
+            lang: open.info.to_string(),
+            content: lines[i + 1..end].join("\n"),
+        });
+        i = end + 1;
+    }
+
+    blocks
+}
+
+fn language_matches(lang: &str, extension: &str) -> bool {
+    let lang = lang.split_whitespace().next().unwrap_or("").to_lowercase();
+    if lang.is_empty() || extension.is_empty() {
+        return false;
+    }
+    if lang == extension {
+        return true;
+    }
+
+    let aliases: &[(&str, &[&str])] = &[
+        ("py", &["python", "python3"]),
+        ("rs", &["rust"]),
+        ("js", &["javascript", "node"]),
+        ("mjs", &["javascript"]),
+        ("jsx", &["javascript"]),
+        ("ts", &["typescript"]),
+        ("tsx", &["typescript"]),
+        ("sh", &["bash", "shell", "zsh"]),
+        ("bash", &["sh", "shell"]),
+        ("zsh", &["sh", "shell", "bash"]),
+        ("yml", &["yaml"]),
+        ("yaml", &["yml"]),
+        ("rb", &["ruby"]),
+        ("kt", &["kotlin"]),
+        ("cs", &["csharp", "c#"]),
+        ("cpp", &["c++", "cxx"]),
+        ("hpp", &["c++", "cpp"]),
+        ("h", &["c", "cpp", "c++"]),
+        ("go", &["golang"]),
+        ("htm", &["html"]),
+        ("ipynb", &["json"]),
+        ("md", &["markdown"]),
+        ("markdown", &["md"]),
+        ("mdx", &["markdown", "md"]),
+    ];
+
+    aliases
+        .iter()
+        .any(|(ext, names)| *ext == extension && names.contains(&lang.as_str()))
+}
+
+fn normalize(content: &str) -> String {
+    let lines: Vec<&str> = content.split('\n').collect();
+    let first = match lines.iter().position(|l| !l.trim().is_empty()) {
+        Some(first) => first,
+        None => return String::new(),
+    };
+
+    let mut result = lines[first..].join("\n").trim_end().to_string();
+    result.push('\n');
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // Each case under tests/fixtures/extract is a directory holding the raw
+    // model reply, the expected file content and the target file name.
+    #[test]
+    fn test_extract_fixtures() {
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/extract");
+        let mut cases: Vec<PathBuf> = fs::read_dir(&root)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_dir())
+            .collect();
+        cases.sort();
+        assert!(!cases.is_empty());
+
+        for case in cases {
+            let reply = fs::read_to_string(case.join("reply.txt")).unwrap();
+            let expected = fs::read_to_string(case.join("expected.txt")).unwrap();
+            let target = fs::read_to_string(case.join("target")).unwrap();
+
+            let actual = extract_file_content(&reply, target.trim());
+            assert_eq!(actual, expected, "fixture {} failed", case.display());
+        }
+    }
+
+    #[test]
+    fn test_extract_crlf() {
+        let reply = "```python\r\nprint('hi')\r\n```\r\n";
+        assert_eq!(extract_file_content(reply, "hi.py"), "print('hi')\n");
+    }
+
+    #[test]
+    fn test_unwrap_reply_keeps_inner_fences() {
+        let notes = "# Notes\n\nInstall it with:\n\n```bash\ncargo install huh\n```\n\nThen run it.\n";
+        assert_eq!(unwrap_reply(notes), notes);
+        assert_eq!(unwrap_reply("```\nplain text\n```\n"), "plain text\n");
+    }
+
+    #[test]
+    fn test_extract_plain_reply_untouched() {
+        let reply = "\n\nfn main() {\n    println!(\"hi\");\n}\n\n";
+        assert_eq!(extract_file_content(reply, "main.rs"), "fn main() {\n    println!(\"hi\");\n}\n");
+    }
+}
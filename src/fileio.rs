@@ -0,0 +1,293 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+const UTF16_LE_BOM: &[u8] = &[0xFF, 0xFE];
+const UTF16_BE_BOM: &[u8] = &[0xFE, 0xFF];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    // Fallback for files that are not valid UTF-8; every byte maps to one char
+    Latin1,
+}
+
+/// An existing file as it was read, with everything needed to write new
+/// content back in the same format and to detect concurrent changes.
+#[derive(Debug, Clone)]
+pub struct FileSnapshot {
+    /// Decoded content with `\n` line endings and without a BOM
+    pub content: String,
+    pub line_ending: LineEnding,
+    pub encoding: Encoding,
+    pub has_bom: bool,
+    pub final_newline: bool,
+    permissions: fs::Permissions,
+    modified: Option<SystemTime>,
+    hash: u64,
+}
+
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Resolves symlinks so that writing replaces the target rather than the link.
+fn resolve_target(path: &Path) -> PathBuf {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_symlink() => fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Reads `path`, returning `None` if it does not exist.
+pub fn read_snapshot(path: &str) -> Result<Option<FileSnapshot>, String> {
+    let target = resolve_target(Path::new(path));
+    let metadata = match fs::metadata(&target) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read existing file: {}", e)),
+    };
+    let bytes = fs::read(&target).map_err(|e| format!("Failed to read existing file: {}", e))?;
+
+    let (encoding, has_bom, text) = decode(&bytes)?;
+    let crlf = text.matches("\r\n").count();
+    let lf = text.matches('\n').count() - crlf;
+    let line_ending = if crlf > lf { LineEnding::CrLf } else { LineEnding::Lf };
+
+    Ok(Some(FileSnapshot {
+        final_newline: text.ends_with('\n'),
+        content: text.replace("\r\n", "\n"),
+        line_ending,
+        encoding,
+        has_bom,
+        permissions: metadata.permissions(),
+        modified: metadata.modified().ok(),
+        hash: hash_bytes(&bytes),
+    }))
+}
+
+fn decode(bytes: &[u8]) -> Result<(Encoding, bool, String), String> {
+    if let Some(rest) = bytes.strip_prefix(UTF8_BOM) {
+        let text = String::from_utf8(rest.to_vec()).map_err(|e| format!("Invalid UTF-8: {}", e))?;
+        return Ok((Encoding::Utf8, true, text));
+    }
+    if let Some(rest) = bytes.strip_prefix(UTF16_LE_BOM) {
+        return Ok((Encoding::Utf16Le, true, decode_utf16(rest, u16::from_le_bytes)?));
+    }
+    if let Some(rest) = bytes.strip_prefix(UTF16_BE_BOM) {
+        return Ok((Encoding::Utf16Be, true, decode_utf16(rest, u16::from_be_bytes)?));
+    }
+    match String::from_utf8(bytes.to_vec()) {
+        Ok(text) => Ok((Encoding::Utf8, false, text)),
+        Err(_) => Ok((Encoding::Latin1, false, bytes.iter().map(|&b| b as char).collect())),
+    }
+}
+
+fn decode_utf16(bytes: &[u8], from_bytes: fn([u8; 2]) -> u16) -> Result<String, String> {
+    if !bytes.len().is_multiple_of(2) {
+        return Err("Invalid UTF-16: odd number of bytes".to_string());
+    }
+    let units: Vec<u16> = bytes.chunks_exact(2).map(|pair| from_bytes([pair[0], pair[1]])).collect();
+    String::from_utf16(&units).map_err(|e| format!("Invalid UTF-16: {}", e))
+}
+
+impl FileSnapshot {
+    /// Converts new `\n`-separated content back to this file's line endings,
+    /// final-newline convention, BOM and encoding.
+    pub fn restore_format(&self, content: &str) -> Result<Vec<u8>, String> {
+        let mut text = content.replace("\r\n", "\n");
+        if self.final_newline {
+            if !text.is_empty() && !text.ends_with('\n') {
+                text.push('\n');
+            }
+        } else {
+            while text.ends_with('\n') {
+                text.pop();
+            }
+        }
+        if self.line_ending == LineEnding::CrLf {
+            text = text.replace('\n', "\r\n");
+        }
+
+        let mut bytes = Vec::new();
+        match self.encoding {
+            Encoding::Utf8 => {
+                if self.has_bom {
+                    bytes.extend_from_slice(UTF8_BOM);
+                }
+                bytes.extend_from_slice(text.as_bytes());
+            }
+            Encoding::Utf16Le => {
+                bytes.extend_from_slice(UTF16_LE_BOM);
+                bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+            }
+            Encoding::Utf16Be => {
+                bytes.extend_from_slice(UTF16_BE_BOM);
+                bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+            }
+            Encoding::Latin1 => {
+                for c in text.chars() {
+                    let code = c as u32;
+                    if code > 0xFF {
+                        return Err(format!(
+                            "The new content contains '{}', which cannot be stored in this file's single-byte encoding",
+                            c
+                        ));
+                    }
+                    bytes.push(code as u8);
+                }
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Fails if the file was modified after this snapshot was taken.
+    fn ensure_unchanged(&self, target: &Path) -> Result<(), String> {
+        let metadata = fs::metadata(target)
+            .map_err(|e| format!("{} disappeared while editing: {}", target.display(), e))?;
+        let bytes = fs::read(target).map_err(|e| format!("Failed to re-read {}: {}", target.display(), e))?;
+
+        if metadata.modified().ok() != self.modified || hash_bytes(&bytes) != self.hash {
+            return Err(format!(
+                "{} was changed by another program while editing; no changes were written",
+                target.display()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Writes `bytes` through a temporary file in the same directory followed
+/// by a rename, so the target is never left half-written. When `snapshot`
+/// is given the write is aborted if the file changed since it was read, and
+/// its permissions are carried over.
+pub fn write_atomic(path: &str, bytes: &[u8], snapshot: Option<&FileSnapshot>) -> Result<(), String> {
+    let target = resolve_target(Path::new(path));
+
+    match snapshot {
+        Some(snapshot) => snapshot.ensure_unchanged(&target)?,
+        None => {
+            if target.exists() {
+                return Err(format!("{} was created by another program while editing; no changes were written", path));
+            }
+        }
+    }
+
+    let parent = match target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    fs::create_dir_all(&parent).map_err(|e| format!("Failed to create parent directories: {}", e))?;
+
+    let file_name = target.file_name().and_then(|n| n.to_str()).unwrap_or("file");
+    let temp_path = parent.join(format!(".{}.huh-{}.tmp", file_name, std::process::id()));
+
+    let result = (|| {
+        let mut file = fs::File::create(&temp_path).map_err(|e| format!("Failed to create temporary file: {}", e))?;
+        file.write_all(bytes).map_err(|e| format!("Failed to write file: {}", e))?;
+        file.sync_all().map_err(|e| format!("Failed to write file: {}", e))?;
+        if let Some(snapshot) = snapshot {
+            fs::set_permissions(&temp_path, snapshot.permissions.clone())
+                .map_err(|e| format!("Failed to preserve file permissions: {}", e))?;
+        }
+        fs::rename(&temp_path, &target).map_err(|e| format!("Failed to replace file: {}", e))
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn temp_file(dir: &TempDir, name: &str, bytes: &[u8]) -> String {
+        let path = dir.join(name);
+        fs::write(&path, bytes).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn test_preserves_crlf_bom_and_missing_final_newline() {
+        let dir = TempDir::new("fileio-crlf");
+        let path = temp_file(&dir, "crlf.txt", b"\xEF\xBB\xBFone\r\ntwo");
+        let snapshot = read_snapshot(&path).unwrap().unwrap();
+        assert_eq!(snapshot.content, "one\ntwo");
+        assert_eq!(snapshot.line_ending, LineEnding::CrLf);
+        assert!(snapshot.has_bom);
+        assert!(!snapshot.final_newline);
+
+        let bytes = snapshot.restore_format("one\ntwo\nthree\n").unwrap();
+        assert_eq!(bytes, b"\xEF\xBB\xBFone\r\ntwo\r\nthree");
+    }
+
+    #[test]
+    fn test_utf16_round_trip() {
+        let mut original = UTF16_LE_BOM.to_vec();
+        original.extend("héllo\n".encode_utf16().flat_map(u16::to_le_bytes));
+        let dir = TempDir::new("fileio-utf16");
+        let path = temp_file(&dir, "utf16.txt", &original);
+
+        let snapshot = read_snapshot(&path).unwrap().unwrap();
+        assert_eq!(snapshot.encoding, Encoding::Utf16Le);
+        assert_eq!(snapshot.content, "héllo\n");
+        assert_eq!(snapshot.restore_format("héllo\n").unwrap(), original);
+    }
+
+    #[test]
+    fn test_latin1_rejects_unencodable_characters() {
+        let dir = TempDir::new("fileio-latin1");
+        let path = temp_file(&dir, "latin1.txt", b"caf\xE9\n");
+        let snapshot = read_snapshot(&path).unwrap().unwrap();
+        assert_eq!(snapshot.encoding, Encoding::Latin1);
+        assert_eq!(snapshot.content, "café\n");
+        assert_eq!(snapshot.restore_format("café!\n").unwrap(), b"caf\xE9!\n");
+        assert!(snapshot.restore_format("caf€\n").is_err());
+    }
+
+    #[test]
+    fn test_write_atomic_detects_concurrent_change() {
+        let dir = TempDir::new("fileio-race");
+        let path = temp_file(&dir, "race.txt", b"before\n");
+        let snapshot = read_snapshot(&path).unwrap().unwrap();
+
+        fs::write(&path, b"someone else\n").unwrap();
+        assert!(write_atomic(&path, b"mine\n", Some(&snapshot)).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"someone else\n");
+
+        let snapshot = read_snapshot(&path).unwrap().unwrap();
+        write_atomic(&path, b"mine\n", Some(&snapshot)).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"mine\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomic_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("fileio-mode");
+        let path = temp_file(&dir, "script.sh", b"echo hi\n");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        let snapshot = read_snapshot(&path).unwrap().unwrap();
+
+        write_atomic(&path, b"echo bye\n", Some(&snapshot)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o755);
+    }
+}
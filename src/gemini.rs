@@ -1,13 +1,32 @@
 use crate::config::Config;
+use crate::context::{self, ContextFile, Selection};
+use crate::edit::{self, EditProtocol};
+use crate::extract;
+use crate::fileio::{self, FileSnapshot};
+use crate::git::{self, GitStatus};
+use crate::guard;
 use crate::history::CommandEntry;
+use crate::hunks;
+use crate::markdown::{self, MarkdownRenderer};
+use crate::notebook::{self, Notebook};
+use crate::output::{self, Palette};
+use crate::pager;
+use crate::report::{FileChange, Timings, TokenUsage};
+use crate::spinner::Spinner;
+use crate::structured::{self, DataFormat};
+use crate::theme;
+use crate::validate::{self, CheckOutcome};
+use dialoguer::{Confirm, Select, theme::ColorfulTheme};
 use regex::Regex;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use similar::{ChangeTag, TextDiff};
-use syntect::easy::HighlightLines;
-use syntect::highlighting::ThemeSet;
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use syntect::highlighting::Theme;
 use syntect::parsing::SyntaxSet;
-use syntect::util::as_24_bit_terminal_escaped;
 
 #[derive(Serialize)]
 struct GeminiRequest {
@@ -16,12 +35,30 @@ struct GeminiRequest {
     generation_config: GenerationConfig,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 struct Content {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<String>,
     parts: Vec<Part>,
 }
 
-#[derive(Serialize)]
+impl Content {
+    fn user(text: String) -> Self {
+        Content {
+            role: Some("user".to_string()),
+            parts: vec![Part { text }],
+        }
+    }
+
+    fn model(text: String) -> Self {
+        Content {
+            role: Some("model".to_string()),
+            parts: vec![Part { text }],
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
 struct Part {
     text: String,
 }
@@ -31,57 +68,305 @@ struct GenerationConfig {
     temperature: f32,
     #[serde(rename = "maxOutputTokens")]
     max_output_tokens: u32,
+    #[serde(rename = "responseMimeType", skip_serializing_if = "Option::is_none")]
+    response_mime_type: Option<String>,
+    #[serde(rename = "responseSchema", skip_serializing_if = "Option::is_none")]
+    response_schema: Option<serde_json::Value>,
 }
 
 #[derive(Deserialize)]
 struct GeminiResponse {
+    #[serde(default)]
     candidates: Vec<Candidate>,
+    #[serde(rename = "promptFeedback")]
+    prompt_feedback: Option<PromptFeedback>,
+    #[serde(rename = "usageMetadata", default)]
+    usage_metadata: UsageMetadata,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct UsageMetadata {
+    #[serde(default)]
+    prompt_token_count: u64,
+    #[serde(default)]
+    candidates_token_count: u64,
+    #[serde(default)]
+    total_token_count: u64,
 }
 
 #[derive(Deserialize)]
 struct Candidate {
-    content: ResponseContent,
+    content: Option<ResponseContent>,
+    #[serde(rename = "finishReason")]
+    finish_reason: Option<String>,
+    #[serde(rename = "safetyRatings", default)]
+    safety_ratings: Vec<SafetyRating>,
 }
 
 #[derive(Deserialize)]
 struct ResponseContent {
+    #[serde(default)]
     parts: Vec<ResponsePart>,
 }
 
 #[derive(Deserialize)]
 struct ResponsePart {
+    #[serde(default)]
     text: String,
 }
 
+#[derive(Deserialize)]
+struct PromptFeedback {
+    #[serde(rename = "blockReason")]
+    block_reason: Option<String>,
+    #[serde(rename = "safetyRatings", default)]
+    safety_ratings: Vec<SafetyRating>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SafetyRating {
+    pub category: String,
+    pub probability: String,
+    #[serde(default)]
+    pub blocked: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FinishReason {
+    Stop,
+    MaxTokens,
+    Safety,
+    Other(String),
+}
+
+impl FinishReason {
+    fn parse(reason: Option<&str>) -> Self {
+        match reason {
+            None | Some("STOP") | Some("FINISH_REASON_UNSPECIFIED") => FinishReason::Stop,
+            Some("MAX_TOKENS") => FinishReason::MaxTokens,
+            Some("SAFETY") => FinishReason::Safety,
+            Some(other) => FinishReason::Other(other.to_string()),
+        }
+    }
+}
+
+/// A single model reply together with the reason generation stopped.
+#[derive(Debug, Clone)]
+pub struct Generation {
+    pub text: String,
+    pub finish_reason: FinishReason,
+    pub safety_ratings: Vec<SafetyRating>,
+    pub usage: TokenUsage,
+}
+
+impl Generation {
+    /// Describes the safety categories that were flagged, most severe first.
+    pub fn flagged_categories(&self) -> String {
+        let mut flagged: Vec<&SafetyRating> = self
+            .safety_ratings
+            .iter()
+            .filter(|r| r.blocked || matches!(r.probability.as_str(), "MEDIUM" | "HIGH"))
+            .collect();
+        flagged.sort_by_key(|r| if r.probability == "HIGH" { 0 } else { 1 });
+
+        flagged
+            .iter()
+            .map(|r| {
+                let category = r.category.trim_start_matches("HARM_CATEGORY_").to_lowercase().replace('_', " ");
+                format!("{} ({})", category, r.probability.to_lowercase())
+            })
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+}
+
+/// Options for write mode that come from command-line flags.
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    /// Show the change as a `git diff` instead of the concise summary
+    pub git_diff: bool,
+    /// For JSON and TOML files, show which keys changed instead of a line diff
+    pub semantic_diff: bool,
+    /// Choose which diff hunks to apply, like `git add -p`
+    pub patch: bool,
+    /// Files included read-only as context for the edit
+    pub references: Vec<ContextFile>,
+    /// Protected paths the user explicitly allowed with `--allow-path`
+    pub allowed_paths: Vec<String>,
+    /// Limit the edit to a line range or symbol of the target
+    pub selection: Option<Selection>,
+}
+
+/// A command analysis, in markdown without terminal styling.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Analysis {
+    pub analysis: String,
+    pub next_steps: Vec<String>,
+    /// The most likely corrected command, if the command looks mistaken
+    pub suggestion: Option<String>,
+    pub suggestions: Vec<Suggestion>,
+    /// Between 0 and 1; only structured replies report it
+    pub confidence: Option<f64>,
+    /// The model's reply as written; only free-form replies keep it
+    #[serde(skip)]
+    pub reply: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Suggestion {
+    pub command: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub explanation: Option<String>,
+}
+
+impl Analysis {
+    /// The analysis as a single markdown document, for `--raw`: the reply
+    /// unchanged when it was free-form, or else a normalized document built
+    /// from the structured reply.
+    pub fn to_markdown(&self) -> String {
+        if let Some(reply) = &self.reply {
+            return reply.clone();
+        }
+        let mut text = format!("**Analysis:**\n{}", self.analysis);
+        if !self.next_steps.is_empty() {
+            text.push_str("\n\n**Next Steps:**");
+            for (i, step) in self.next_steps.iter().enumerate() {
+                text.push_str(&format!("\n{}. {}", i + 1, step));
+            }
+        }
+        for (i, suggestion) in self.suggestions.iter().enumerate() {
+            if i == 0 {
+                text.push_str(&format!("\n\nDid you mean: `{}`", suggestion.command));
+            } else {
+                text.push_str(&format!("\nor: `{}`", suggestion.command));
+            }
+            if let Some(explanation) = &suggestion.explanation {
+                text.push_str(&format!(" - {}", explanation));
+            }
+        }
+        text
+    }
+}
+
+/// The reply to a structured analysis request, as described by `analysis_schema`.
+#[derive(Deserialize)]
+struct StructuredAnalysis {
+    analysis: String,
+    #[serde(default)]
+    steps: Vec<String>,
+    #[serde(default)]
+    suggestions: Vec<Suggestion>,
+    confidence: Option<f64>,
+}
+
+impl From<StructuredAnalysis> for Analysis {
+    fn from(reply: StructuredAnalysis) -> Self {
+        let suggestions: Vec<Suggestion> = reply
+            .suggestions
+            .into_iter()
+            .filter(|s| !s.command.trim().is_empty())
+            .collect();
+        Analysis {
+            analysis: reply.analysis.trim().to_string(),
+            next_steps: reply.steps.into_iter().filter(|step| !step.trim().is_empty()).collect(),
+            suggestion: suggestions.first().map(|s| s.command.trim().to_string()),
+            suggestions,
+            confidence: reply.confidence.map(|c| c.clamp(0.0, 1.0)),
+            reply: None,
+        }
+    }
+}
+
+/// A checked edit, not yet written to disk.
+pub struct ProposedEdit {
+    pub path: String,
+    pub original: String,
+    pub new_content: String,
+    /// `None` when the file is being created
+    pub snapshot: Option<FileSnapshot>,
+    // What diffs and hunks compare: notebook cell sources, or the file itself
+    working: String,
+    edited_working: String,
+    compares_cells: bool,
+    check_command: Option<String>,
+}
+
+impl ProposedEdit {
+    /// `content` in the file's original encoding and line endings.
+    pub fn encode(&self, content: &str) -> Result<Vec<u8>, String> {
+        encode_for(self.snapshot.as_ref(), content)
+    }
+}
+
+enum EditReply {
+    Complete(String),
+    SwitchToSearchReplace,
+}
+
+/// Totals over every request a client has made, for `--format json`.
+#[derive(Default)]
+struct RequestStats {
+    requests: u32,
+    api_time: Duration,
+    usage: TokenUsage,
+}
+
 pub struct GeminiClient {
     client: Client,
     api_key: String,
     model: String,
     syntax_set: SyntaxSet,
-    theme_set: ThemeSet,
+    // Resolved on first use, since `auto` queries the terminal
+    theme: OnceLock<Theme>,
     config: Config,
+    stats: Mutex<RequestStats>,
 }
 
 impl GeminiClient {
     pub fn new(api_key: String, model: String, config: &Config) -> Self {
         let syntax_set = SyntaxSet::load_defaults_newlines();
-        let theme_set = ThemeSet::load_defaults();
         Self {
             client: Client::new(),
             api_key,
             model,
             syntax_set,
-            theme_set,
+            theme: OnceLock::new(),
             config: config.clone(),
+            stats: Mutex::new(RequestStats::default()),
+        }
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// Tokens used by all requests so far.
+    pub fn usage(&self) -> TokenUsage {
+        self.stats.lock().map(|stats| stats.usage).unwrap_or_default()
+    }
+
+    /// Time spent on requests so far, and in total since `started`.
+    pub fn timings(&self, started: Instant) -> Timings {
+        let (requests, api_time) = self
+            .stats
+            .lock()
+            .map(|stats| (stats.requests, stats.api_time))
+            .unwrap_or_default();
+        Timings::new(requests, api_time, started.elapsed())
+    }
+
+    /// Shows a colored unified diff, through the pager when it is long.
+    fn display_full_diff(&self, original: &str, new_content: &str, file_path: &str) {
+        let diff = format!("\n{}", git::colorize_diff(&git::unified_diff(original, new_content, file_path)));
+        if !pager::page(&diff, &self.config.pager) {
+            output::say(diff);
         }
     }
 
     fn display_diff(&self, original: &str, new_content: &str, _file_path: &str) {
-        const RED: &str = "\x1b[31m";
-        const BLUE: &str = "\x1b[34m";     // Changed from GREEN to BLUE for additions
-        const YELLOW: &str = "\x1b[33m";
-        const RESET: &str = "\x1b[0m";
-        const BOLD: &str = "\x1b[1m";
+        let Palette { red, yellow, blue, bold, reset, .. } = output::palette();
 
         let diff = TextDiff::from_lines(original, new_content);
         
@@ -100,9 +385,9 @@ impl GeminiClient {
         }
         
         // Always show concise summary
-        println!("\n  {}{}{}{} additions (+), {}{}{}{} deletions (-)", 
-            BLUE, BOLD, total_additions, RESET,
-            RED, BOLD, total_deletions, RESET);
+        output::say(format_args!("\n  {}{}{}{} additions (+), {}{}{}{} deletions (-)", 
+            blue, bold, total_additions, reset,
+            red, bold, total_deletions, reset));
         
         // Show a few key changes as preview
         let preview_lines = 5;
@@ -116,32 +401,22 @@ impl GeminiClient {
                 match change.tag() {
                     ChangeTag::Delete => {
                         if !has_changes {
-                            println!("\n{}Key changes:{}", BOLD, RESET);
+                            output::say(format_args!("\n{}Key changes:{}", bold, reset));
                             has_changes = true;
                         }
-                        print!("  {}-{} ", RED, RESET);
                         let line = change.value().trim_end();
-                        let truncated = if line.len() > 60 { 
-                            format!("{}...", &line[..57]) 
-                        } else { 
-                            line.to_string() 
-                        };
-                        println!("{}{}{}", RED, truncated, RESET);
+                        let truncated = markdown::truncate_to_width(line, 60);
+                        output::say(format_args!("  {}-{} {}{}{}", red, reset, red, truncated, reset));
                         shown_lines += 1;
                     },
                     ChangeTag::Insert => {
                         if !has_changes {
-                            println!("\n{}Key changes:{}", BOLD, RESET);
+                            output::say(format_args!("\n{}Key changes:{}", bold, reset));
                             has_changes = true;
                         }
-                        print!("  {}+{} ", BLUE, RESET);
                         let line = change.value().trim_end();
-                        let truncated = if line.len() > 60 { 
-                            format!("{}...", &line[..57]) 
-                        } else { 
-                            line.to_string() 
-                        };
-                        println!("{}{}{}", BLUE, truncated, RESET);
+                        let truncated = markdown::truncate_to_width(line, 60);
+                        output::say(format_args!("  {}+{} {}{}{}", blue, reset, blue, truncated, reset));
                         shown_lines += 1;
                     },
                     ChangeTag::Equal => {},
@@ -151,147 +426,644 @@ impl GeminiClient {
         
         let total_changes = total_additions + total_deletions;
         if total_changes > preview_lines {
-            println!("  {}... and {} more changes{}", YELLOW, total_changes - shown_lines, RESET);
+            output::say(format_args!("  {}... and {} more changes{}", yellow, total_changes - shown_lines, reset));
         }
         
     }
 
-    pub async fn analyze_commands(
-        &self,
-        commands: &[CommandEntry],
-    ) -> Result<(String, Option<String>), String> {
+    pub async fn analyze_commands(&self, commands: &[CommandEntry]) -> Result<Analysis, String> {
+        if self.config.structured_analysis {
+            if let Some(analysis) = self.analyze_structured(commands).await? {
+                return Ok(analysis);
+            }
+        }
         let prompt = self.format_prompt(commands);
+        let gemini_text = self.generate(vec![Content::user(prompt)]).await?.text;
+        Ok(parse_analysis(&gemini_text))
+    }
 
-        let request = GeminiRequest {
-            contents: vec![Content {
-                parts: vec![Part { text: prompt }],
-            }],
-            generation_config: GenerationConfig {
-                temperature: self.config.temperature,
-                max_output_tokens: self.config.max_output_tokens,
-            },
+    /// Asks for the analysis as JSON matching `analysis_schema`. Returns
+    /// `None` if the backend rejects the schema or the reply does not match
+    /// it, so the caller can fall back to a free-form reply.
+    async fn analyze_structured(&self, commands: &[CommandEntry]) -> Result<Option<Analysis>, String> {
+        let prompt = format!(
+            "{}Reply with a JSON object about the last command only:\n\
+            - analysis: a brief analysis of the command and its output, in markdown\n\
+            - steps: relevant next steps, one short markdown string each\n\
+            - suggestions: if the command looks like a typo or mistake, the corrected commands to run, most likely \
+            first, each with a short explanation; otherwise an empty list\n\
+            - confidence: how confident you are in the analysis, from 0 to 1\n\n\
+            {}",
+            self.format_history(commands),
+            self.config.get_response_length_instruction()
+        );
+
+        let generation = match self.generate_json(vec![Content::user(prompt)], analysis_schema()).await {
+            Ok(generation) => generation,
+            // Backends without schema support reject the request as invalid
+            Err(e) if e.contains("INVALID_ARGUMENT") => return Ok(None),
+            Err(e) => return Err(e),
         };
+        Ok(parse_structured_analysis(&generation.text))
+    }
 
-        let url = format!(
-            "https://generativelanguage.googleapis.com/v1beta/models/{}:generateContent?key={}",
-            self.model, self.api_key
+    /// Formats an analysis for the terminal.
+    pub fn render_analysis(&self, analysis: &Analysis) -> String {
+        let Palette { red, yellow, blue, dim, reset, .. } = output::palette();
+
+        let confidence = match analysis.confidence {
+            Some(confidence) => format!(" {}({:.0}% confident){}", dim, confidence * 100.0, reset),
+            None => String::new(),
+        };
+        let mut text = format!(
+            "\n{}Analysis:{}{}\n{}",
+            blue,
+            reset,
+            confidence,
+            self.convert_markdown_to_ansi(&analysis.analysis)
         );
+        if !analysis.next_steps.is_empty() {
+            let steps: Vec<String> = analysis
+                .next_steps
+                .iter()
+                .enumerate()
+                .map(|(i, step)| format!("{}. {}", i + 1, step))
+                .collect();
+            text.push_str(&format!(
+                "\n\n{}Next Steps:{}\n{}",
+                yellow,
+                reset,
+                self.convert_markdown_to_ansi(&steps.join("\n"))
+            ));
+        }
+        if let Some(suggestion) = &analysis.suggestion {
+            text.push_str(&format!("\n\n\n{}Did you mean:{}\n{}", red, reset, suggestion));
+            for other in analysis.suggestions.iter().skip(1) {
+                text.push_str(&format!("\n{}", other.command));
+            }
+            if let Some(explanation) = analysis.suggestions.first().and_then(|s| s.explanation.as_ref()) {
+                text.push_str(&format!("\n{}{}{}", dim, explanation, reset));
+            }
+        }
+        text
+    }
 
-        let response = self
-            .client
-            .post(&url)
-            .header("Content-Type", "application/json")
-            .json(&request)
-            .send()
-            .await
-            .map_err(|e| format!("Request failed: {}", e))?;
+    pub async fn write_or_edit_file(&self, file_path: &str, context: &str, options: &WriteOptions) -> Result<FileChange, String> {
+        guard::ensure_writable(file_path, &self.config, &options.allowed_paths)?;
 
-        if !response.status().is_success() {
-            let error_text = response.text().await.unwrap_or_default();
-            return Err(format!("API error: {}", error_text));
+        let git_status = git::file_status(file_path);
+        if let Some(ref status) = git_status {
+            self.check_git_status(file_path, Path::new(file_path).exists(), status)?;
         }
 
-        let gemini_response: GeminiResponse = response
-            .json()
-            .await
-            .map_err(|e| format!("Failed to parse response: {}", e))?;
+        let edit = self.prepare_edit(file_path, context, options, true).await?;
+        let file_exists = edit.snapshot.is_some();
+        let original_content = edit.original.as_str();
+        let cleaned_content = edit.new_content.as_str();
 
-        let mut gemini_text = gemini_response
-            .candidates
-            .into_iter()
-            .next()
-            .and_then(|c| c.content.parts.into_iter().next())
-            .map(|p| p.text)
-            .ok_or_else(|| "No response from Gemini".to_string())?;
-
-        const BLUE: &str = "\x1b[34m";
-        const YELLOW: &str = "\x1b[33m";
-
-        const RED: &str = "\x1b[31m";
-        const RESET: &str = "\x1b[0m";
-
-        let mut suggestion = None;
-
-        let suggestion_capture_regex =
-            Regex::new(r"(?im)^did you mean[:\s`*]*([^`*\n\r]+?)[`*\s]*$").unwrap();
-        if let Some(caps) = suggestion_capture_regex.captures(&gemini_text) {
-            suggestion = Some(caps.get(1).unwrap().as_str().trim().to_string());
-        }
-
-        let cleanup_did_you_mean_regex = Regex::new(r"(?im)^did you mean[:\s`*].*$").unwrap();
-        let mut cleaned_text = cleanup_did_you_mean_regex
-            .replace_all(&gemini_text, "")
-            .to_string();
-
-        if let Some(ref sugg) = suggestion {
-            let escaped_sugg = regex::escape(sugg);
-            let suggestion_removal_regex = Regex::new(&format!(r"(?i){}", escaped_sugg)).unwrap();
-            cleaned_text = suggestion_removal_regex
-                .replace_all(&cleaned_text, "")
-                .to_string();
-        }
-
-        let extra_newlines_regex = Regex::new(r"\n{3,}").unwrap();
-        gemini_text = extra_newlines_regex
-            .replace_all(&cleaned_text, "\n\n")
-            .to_string()
-            .trim()
-            .to_string();
-
-        gemini_text = self.convert_markdown_to_ansi(&gemini_text);
-
-        let analysis_regex = Regex::new(r"(?i)Analysis:").unwrap();
-        gemini_text = analysis_regex
-            .replace_all(
-                &gemini_text,
-                &format!(
-                    "
-{}{}{}",
-                    BLUE, "Analysis:", RESET
-                ),
-            )
-            .to_string();
-
-        let next_steps_regex = Regex::new(r"(?i)Next Steps:").unwrap();
-        gemini_text = next_steps_regex
-            .replace_all(
-                &gemini_text,
-                &format!(
-                    "
-
-{}{}{}",
-                    YELLOW, "Next Steps:", RESET
-                ),
-            )
-            .to_string();
+        // Show diff if file exists and content changed
+        if file_exists && original_content.trim() != cleaned_content.trim() {
+            self.display_edit(&edit, options);
+        } else if !file_exists {
+            output::say(format_args!("\n+ Creating new file: {}", file_path));
+            if options.git_diff {
+                self.display_full_diff("", cleaned_content, file_path);
+            }
+        } else {
+            output::say("\n✓ No changes needed - file content is already up to date");
+            return Ok(FileChange::new(file_path, true, original_content, original_content));
+        }
 
-        if let Some(ref sugg) = suggestion {
-            gemini_text.push_str(&format!(
-                "
+        let mut selected_content = cleaned_content.to_string();
+        if options.patch && file_exists {
+            selected_content = match self.choose_hunks(&edit).await? {
+                Some(content) => content,
+                None => {
+                    output::say("\nNo hunks selected; no changes were written");
+                    return Ok(FileChange::new(file_path, true, original_content, original_content));
+                }
+            };
+        }
+        let cleaned_content = selected_content.as_str();
 
+        // Write the file in its original format, atomically
+        let bytes = edit.encode(cleaned_content)?;
+        fileio::write_atomic(file_path, &bytes, edit.snapshot.as_ref())?;
 
-{}{}{}
-{}",
-                RED,
-                "Did you mean:",
-                RESET,
-                sugg.trim()
-            ));
+        if let Some(status) = git_status {
+            if self.config.git_offer_commit && io::stdin().is_terminal() {
+                self.offer_git_commit(file_path, context, original_content, cleaned_content, status.dirty)
+                    .await;
+            }
         }
 
-        Ok((gemini_text, suggestion))
+        Ok(FileChange::new(file_path, file_exists, original_content, cleaned_content))
     }
 
-    pub async fn write_or_edit_file(&self, file_path: &str, context: &str) -> Result<(), String> {
-        let file_exists = std::fs::metadata(file_path).is_ok();
-        let original_content = if file_exists {
-            std::fs::read_to_string(file_path)
-                .map_err(|e| format!("Failed to read existing file: {}", e))?
+    /// Produces the edited content of an existing file without any
+    /// interaction, for batch edits. It goes through the same checks and
+    /// repairs as `write_or_edit_file`, but truncated replies and edits that
+    /// still fail their check are refused instead of prompting.
+    pub async fn propose_edit(&self, file_path: &str, context: &str, options: &WriteOptions) -> Result<ProposedEdit, String> {
+        if !Path::new(file_path).exists() {
+            return Err(format!("{} does not exist", file_path));
+        }
+        self.prepare_edit(file_path, context, options, false).await
+    }
+
+    /// Generates, parses, checks and repairs an edit without writing it.
+    /// With `interactive` unset nothing is printed or asked, so that several
+    /// edits can run at once.
+    async fn prepare_edit(
+        &self,
+        file_path: &str,
+        context: &str,
+        options: &WriteOptions,
+        interactive: bool,
+    ) -> Result<ProposedEdit, String> {
+        let snapshot = fileio::read_snapshot(file_path)?;
+        let file_exists = snapshot.is_some();
+        let original_content = snapshot.as_ref().map(|s| s.content.clone()).unwrap_or_default();
+
+        // Notebooks are edited as their cell sources, without outputs or metadata
+        let mut notebook = if file_exists && notebook::is_notebook(file_path) {
+            Some(Notebook::parse(&original_content)?)
         } else {
-            String::new()
+            None
         };
-        
-        let prompt = if file_exists {
+        let working_content = match &notebook {
+            Some(notebook) => notebook.render(),
+            None => original_content.clone(),
+        };
+        let mut instructions = context.to_string();
+        if notebook.is_some() {
+            instructions = format!("{}\n\n{}", instructions, notebook::EDIT_NOTES);
+        }
+        let references: Vec<ContextFile> = options
+            .references
+            .iter()
+            .filter(|reference| guard::normalize(Path::new(&reference.path)) != guard::normalize(Path::new(file_path)))
+            .cloned()
+            .collect();
+        if !references.is_empty() {
+            instructions = format!(
+                "{}\n\nThe files below are read-only references. Use them for context only: do not change them and do not \
+                include them in your output. The only file you may change is {}.\n\n{}",
+                instructions,
+                file_path,
+                context::format_context(&references)
+            );
+        }
+
+        // With @file:N-M or @file#symbol only that region is sent and replaced
+        let region = match &options.selection {
+            Some(selection) => {
+                if !file_exists {
+                    return Err(format!("{} does not exist, so part of it cannot be selected", file_path));
+                }
+                Some(context::resolve_selection(&working_content, selection, file_path)?)
+            }
+            None => None,
+        };
+        let (before, editable, after) = match region {
+            Some((start, end)) => context::split_region(&working_content, start, end),
+            None => (String::new(), working_content.clone(), String::new()),
+        };
+        let excerpt = region.map(|(start, end)| (start + 1, end));
+
+        let mut protocol = edit::choose_protocol(
+            &self.config.edit_protocol,
+            file_exists.then_some(editable.as_str()),
+            self.config.whole_file_max_lines,
+        );
+
+        let file_content = loop {
+            let prompt = self.build_edit_prompt(file_path, &editable, file_exists, &instructions, protocol, excerpt);
+            let can_switch = file_exists && protocol == EditProtocol::WholeFile;
+            match self.generate_edit(prompt, can_switch, interactive).await? {
+                EditReply::Complete(text) => break text,
+                EditReply::SwitchToSearchReplace => {
+                    if interactive {
+                        output::say("Retrying with search/replace edit blocks...");
+                    }
+                    protocol = EditProtocol::SearchReplace;
+                }
+            }
+        };
+
+        let mut edited = apply_reply(protocol, &editable, &file_content, file_path)?;
+        if region.is_some() && !editable.ends_with('\n') && edited.ends_with('\n') {
+            edited.pop();
+        }
+        let mut updated_content = format!("{}{}{}", before, edited, after);
+        let mut edited_working_content = updated_content.clone();
+        if let Some(notebook) = notebook.as_mut() {
+            notebook.apply(&updated_content, self.config.notebook_clear_outputs)?;
+            updated_content = notebook.to_json()?;
+        }
+        if let Some(format) = DataFormat::for_path(file_path) {
+            structured::parse(format, &updated_content).map_err(|e| {
+                format!("The edited file is not valid {}: {}; no changes were written", format.name(), e)
+            })?;
+        }
+        let check_command = validate::check_command_for(&self.config.check_commands, file_path);
+        if let Some(command) = check_command {
+            let checked = self
+                .validate_and_repair(file_path, command, updated_content.clone(), snapshot.as_ref(), context, interactive)
+                .await?;
+            // Diffs and hunks come from the repaired content
+            if checked != updated_content {
+                edited_working_content = match &notebook {
+                    Some(_) => Notebook::parse(&checked)?
+                        .render_like(&edited_working_content)
+                        .unwrap_or_else(|| checked.clone()),
+                    None => checked.clone(),
+                };
+                updated_content = checked;
+            }
+        }
+
+        // A repair that adds or removes notebook cells is compared as JSON
+        let compares_cells = notebook.is_some() && edited_working_content != updated_content;
+        let working_content = if compares_cells { working_content } else { original_content.clone() };
+        Ok(ProposedEdit {
+            path: file_path.to_string(),
+            original: original_content,
+            new_content: updated_content,
+            snapshot,
+            working: working_content,
+            edited_working: edited_working_content,
+            compares_cells,
+            check_command: check_command.map(str::to_string),
+        })
+    }
+
+    /// Shows an edit of an existing file: as a key diff with
+    /// `--semantic-diff`, a `git diff` with `--git-diff`, or a line diff.
+    pub fn display_edit(&self, edit: &ProposedEdit, options: &WriteOptions) {
+        let data_format = DataFormat::for_path(&edit.path);
+        let key_diff_shown = options.semantic_diff
+            && data_format.is_some_and(|format| self.display_key_diff(format, &edit.original, &edit.new_content, &edit.path));
+        if key_diff_shown {
+            // The key diff replaces the line diff
+        } else if options.git_diff {
+            self.display_full_diff(&edit.original, &edit.new_content, &edit.path);
+        } else {
+            // Notebooks compare cell sources rather than their JSON
+            self.display_diff(&edit.working, &edit.edited_working, &edit.path);
+        }
+    }
+
+    /// Lets the user pick hunks of the edit, like `git add -p`. Notebooks
+    /// are compared by cell source and rebuilt from the accepted hunks. The
+    /// result is checked again, since it differs from the checked edit.
+    /// Returns `None` if no hunk was accepted.
+    pub async fn choose_hunks(&self, edit: &ProposedEdit) -> Result<Option<String>, String> {
+        let selected = match hunks::select_hunks(&edit.working, &edit.edited_working)? {
+            Some(selected) => selected,
+            None => return Ok(None),
+        };
+        let selected = if edit.compares_cells {
+            let mut notebook = Notebook::parse(&edit.original)?;
+            notebook.apply(&selected, self.config.notebook_clear_outputs)?;
+            notebook.to_json()?
+        } else {
+            selected
+        };
+
+        if let Some(format) = DataFormat::for_path(&edit.path) {
+            structured::parse(format, &selected).map_err(|e| {
+                format!("The selected hunks do not form valid {}: {}; no changes were written", format.name(), e)
+            })?;
+        }
+        if let Some(command) = edit.check_command.as_deref().filter(|_| selected != edit.new_content) {
+            self.recheck_selection(&edit.path, command, &selected, edit.snapshot.as_ref()).await?;
+        }
+        Ok(Some(selected))
+    }
+
+    /// Runs the check command on the hunks the user kept. A failure is not
+    /// sent back for repair, since that could bring back rejected hunks; the
+    /// user decides whether to write anyway.
+    async fn recheck_selection(
+        &self,
+        file_path: &str,
+        command: &str,
+        content: &str,
+        snapshot: Option<&FileSnapshot>,
+    ) -> Result<(), String> {
+        let bytes = encode_for(snapshot, content)?;
+        let spinner = Spinner::start("Checking the selected hunks...");
+        let outcome = validate::run_check(command, file_path, &bytes).await;
+        spinner.stop().await;
+
+        match outcome {
+            CheckOutcome::Passed => {
+                output::say(format_args!("✓ `{}` passed", command));
+                Ok(())
+            }
+            CheckOutcome::Skipped(reason) => {
+                output::warn(&format!("Skipping the check: {}", reason));
+                Ok(())
+            }
+            CheckOutcome::Failed(diagnostics) => {
+                output::warn(&format!("`{}` fails on the selected hunks:\n{}", command, diagnostics));
+                let write_anyway = io::stdin().is_terminal()
+                    && Confirm::with_theme(&ColorfulTheme::default())
+                        .with_prompt("Write the file anyway?")
+                        .default(false)
+                        .interact()
+                        .unwrap_or(false);
+                if write_anyway {
+                    Ok(())
+                } else {
+                    Err(format!("The selected hunks do not pass `{}`; no changes were written", command))
+                }
+            }
+        }
+    }
+
+    /// Prints the keys that changed in a JSON or TOML file. Returns false if
+    /// the format has no key diff or the original does not parse, so the
+    /// caller can fall back to a line diff.
+    fn display_key_diff(&self, format: DataFormat, original: &str, new_content: &str, file_path: &str) -> bool {
+        if !format.has_key_diff() {
+            return false;
+        }
+        let (old_value, new_value) = match (structured::parse(format, original), structured::parse(format, new_content)) {
+            (Ok(old_value), Ok(new_value)) => (old_value, new_value),
+            _ => {
+                output::warn(&format!("The original {} does not parse; showing a line diff instead", file_path));
+                return false;
+            }
+        };
+
+        let changes = structured::semantic_diff(&old_value, &new_value);
+        let Palette { bold, reset, .. } = output::palette();
+        output::say(format_args!("\n{}▲ Key changes for {}:{}", bold, file_path, reset));
+        output::say("─────────────────────────────────────────────────────────────");
+        if changes.is_empty() {
+            output::say("  No keys changed; only formatting or comments differ");
+        } else {
+            for line in structured::format_key_changes(&changes).lines() {
+                output::say(format_args!("  {}", line));
+            }
+        }
+        output::say("─────────────────────────────────────────────────────────────");
+        true
+    }
+
+    /// Runs the configured check command on the edited content and, while it
+    /// fails, sends the diagnostics back to the model for up to
+    /// `max_repair_attempts` fixes.
+    async fn validate_and_repair(
+        &self,
+        file_path: &str,
+        command: &str,
+        mut content: String,
+        snapshot: Option<&FileSnapshot>,
+        context: &str,
+        interactive: bool,
+    ) -> Result<String, String> {
+        let mut attempt = 0;
+        loop {
+            let bytes = encode_for(snapshot, &content)?;
+            let spinner = interactive.then(|| Spinner::start("Checking..."));
+            let outcome = validate::run_check(command, file_path, &bytes).await;
+            if let Some(spinner) = spinner {
+                spinner.stop().await;
+            }
+
+            let diagnostics = match outcome {
+                CheckOutcome::Passed => {
+                    if interactive {
+                        output::say(format_args!("✓ `{}` passed", command));
+                    }
+                    return Ok(content);
+                }
+                CheckOutcome::Skipped(reason) => {
+                    if interactive {
+                        output::warn(&format!("Skipping the check: {}", reason));
+                    }
+                    return Ok(content);
+                }
+                CheckOutcome::Failed(diagnostics) => diagnostics,
+            };
+
+            if attempt >= self.config.max_repair_attempts && !interactive {
+                return Err(format!("`{}` still fails after {} repair attempts", command, attempt));
+            }
+            if attempt >= self.config.max_repair_attempts {
+                output::say("");
+                output::warn(&format!(
+                    "`{}` still fails after {} repair attempts:\n{}",
+                    command, attempt, diagnostics
+                ));
+                let write_anyway = io::stdin().is_terminal()
+                    && Confirm::with_theme(&ColorfulTheme::default())
+                        .with_prompt("Write the file anyway?")
+                        .default(false)
+                        .interact()
+                        .unwrap_or(false);
+                if !write_anyway {
+                    return Err(format!("The edited file does not pass `{}`; no changes were written", command));
+                }
+                return Ok(content);
+            }
+
+            attempt += 1;
+            if interactive {
+                output::warn(&format!(
+                    "`{}` failed; asking the model to fix it (attempt {}/{})",
+                    command, attempt, self.config.max_repair_attempts
+                ));
+            }
+            content = self
+                .repair(file_path, &content, command, &diagnostics, context, interactive)
+                .await?;
+        }
+    }
+
+    async fn repair(
+        &self,
+        file_path: &str,
+        content: &str,
+        command: &str,
+        diagnostics: &str,
+        context: &str,
+        interactive: bool,
+    ) -> Result<String, String> {
+        let mut protocol = edit::choose_protocol(&self.config.edit_protocol, Some(content), self.config.whole_file_max_lines);
+        loop {
+            let output_instructions = if protocol == EditProtocol::SearchReplace {
+                edit::protocol_instructions()
+            } else {
+                "Please provide the complete corrected file content. Only output the file content, no explanations or markdown formatting."
+                    .to_string()
+            };
+            let prompt = format!(
+                "You are a helpful file editor. The following file was just edited with these instructions: {}\n\n\
+                File path: {}\n\n\
+                Current file content:\n\
+                ```\n{}\n```\n\n\
+                Running `{}` on it reports these problems:\n\
+                ```\n{}\n```\n\n\
+                Fix the problems while keeping the requested change.\n\n\
+                {}",
+                context, file_path, content, command, diagnostics, output_instructions
+            );
+            match self.generate_edit(prompt, protocol == EditProtocol::WholeFile, interactive).await? {
+                EditReply::Complete(text) => return apply_reply(protocol, content, &text, file_path),
+                EditReply::SwitchToSearchReplace => {
+                    if interactive {
+                        output::say("Retrying with search/replace edit blocks...");
+                    }
+                    protocol = EditProtocol::SearchReplace;
+                }
+            }
+        }
+    }
+
+    /// Applies `git_dirty_policy` ("warn", "refuse" or "ignore") to a target
+    /// that has uncommitted changes or is not tracked yet.
+    pub fn check_git_status(&self, file_path: &str, file_exists: bool, status: &GitStatus) -> Result<(), String> {
+        let problem = if status.dirty && status.tracked {
+            "has uncommitted changes"
+        } else if file_exists && !status.tracked {
+            "is not tracked by git, so the edit cannot be undone with git"
+        } else {
+            return Ok(());
+        };
+
+        match self.config.git_dirty_policy.as_str() {
+            "ignore" => Ok(()),
+            "refuse" => Err(format!(
+                "{} {}. Commit or stash it first, or set git_dirty_policy to \"warn\"",
+                file_path, problem
+            )),
+            _ => {
+                output::warn(&format!("{} {}", file_path, problem));
+                Ok(())
+            }
+        }
+    }
+
+    async fn offer_git_commit(
+        &self,
+        file_path: &str,
+        context: &str,
+        original_content: &str,
+        new_content: &str,
+        was_dirty: bool,
+    ) {
+        let mut options = vec!["Leave the change unstaged", "Stage the change"];
+        if !was_dirty {
+            // Committing a file that already had local edits would sweep them in too
+            options.push("Commit with a generated message");
+        }
+
+        output::say("");
+        let selection = match Select::with_theme(&ColorfulTheme::default())
+            .with_prompt("Git")
+            .default(0)
+            .items(&options)
+            .interact()
+        {
+            Ok(selection) => selection,
+            Err(_) => return,
+        };
+
+        let result = match options[selection] {
+            "Stage the change" => git::stage(file_path).map(|_| format!("Staged {}", file_path)),
+            "Commit with a generated message" => {
+                let diff = git::unified_diff(original_content, new_content, file_path);
+                match self.generate_commit_message(&diff, context).await {
+                    Ok(message) => {
+                        output::say(format_args!("\n{}\n", message));
+                        let confirmed = Confirm::with_theme(&ColorfulTheme::default())
+                            .with_prompt("Commit with this message?")
+                            .default(true)
+                            .interact()
+                            .unwrap_or(false);
+                        if confirmed {
+                            git::commit(file_path, &message).map(|_| format!("Committed {}", file_path))
+                        } else {
+                            Ok("Commit cancelled; the change is left unstaged".to_string())
+                        }
+                    }
+                    Err(e) => Err(format!("Failed to generate a commit message: {}", e)),
+                }
+            }
+            _ => return,
+        };
+
+        match result {
+            Ok(message) => output::say(format_args!("✓ {}", message)),
+            Err(e) => eprintln!("Warning: {}", e),
+        }
+    }
+
+    async fn generate_commit_message(&self, diff: &str, context: &str) -> Result<String, String> {
+        let prompt = format!(
+            "Write a git commit message for the following change.\n\n\
+            The change was requested with this instruction: {}\n\n\
+            ```diff\n{}\n```\n\n\
+            Use an imperative subject line of at most 72 characters. Add a short body after a blank line only if \
+            the change needs explaining. Output only the commit message, with no quotes or markdown.",
+            context, diff
+        );
+
+        let spinner = Spinner::start("Writing commit message...");
+        let result = self.generate(vec![Content::user(prompt)]).await;
+        spinner.stop().await;
+
+        let message = extract::extract_file_content(&result?.text, "COMMIT_EDITMSG");
+        let message = message.trim();
+        if message.is_empty() {
+            return Err("the model returned an empty message".to_string());
+        }
+        Ok(message.to_string())
+    }
+
+    fn build_edit_prompt(
+        &self,
+        file_path: &str,
+        original_content: &str,
+        file_exists: bool,
+        context: &str,
+        protocol: EditProtocol,
+        excerpt: Option<(usize, usize)>,
+    ) -> String {
+        if let Some((start, end)) = excerpt {
+            // Edit a selected region of an existing file
+            let output_instructions = if protocol == EditProtocol::SearchReplace {
+                edit::protocol_instructions()
+            } else {
+                "Please provide the complete updated content for these lines only. \
+                Only output those lines, no explanations or markdown formatting."
+                    .to_string()
+            };
+            format!(
+                "You are a helpful file editor. I need you to edit part of a file based on my instructions.\n\n\
+                File path: {}\n\n\
+                You may only change lines {}-{} of this file; the rest of the file stays as it is. Current content of those lines:\n\
+                ```\n{}\n```\n\n\
+                Instructions: {}\n\n\
+                {}",
+                file_path, start, end, original_content, context, output_instructions
+            )
+        } else if file_exists && protocol == EditProtocol::SearchReplace {
+            // Edit existing file with targeted search/replace blocks
+            format!(
+                "You are a helpful file editor. I need you to edit the following file based on my instructions.\n\n\
+                File path: {}\n\n\
+                Current file content:\n\
+                ```\n{}\n```\n\n\
+                Instructions: {}\n\n\
+                {}",
+                file_path, original_content, context, edit::protocol_instructions()
+            )
+        } else if file_exists {
             // Edit existing file
             format!(
                 "You are a helpful file editor. I need you to edit the following file based on my instructions.\n\n\
@@ -311,100 +1083,103 @@ impl GeminiClient {
                 Please provide the complete file content that should be written to this file. Only output the file content, no explanations or markdown formatting.",
                 file_path, context
             )
-        };
-
-        let request = GeminiRequest {
-            contents: vec![Content {
-                parts: vec![Part { text: prompt }],
-            }],
-            generation_config: GenerationConfig {
-                temperature: self.config.temperature,
-                max_output_tokens: self.config.max_output_tokens,
-            },
-        };
-
-        let url = format!(
-            "https://generativelanguage.googleapis.com/v1beta/models/{}:generateContent?key={}",
-            self.model, self.api_key
-        );
-
-        let response = self
-            .client
-            .post(&url)
-            .header("Content-Type", "application/json")
-            .json(&request)
-            .send()
-            .await
-            .map_err(|e| format!("Request failed: {}", e))?;
-
-        if !response.status().is_success() {
-            let error_text = response.text().await.unwrap_or_default();
-            return Err(format!("API error: {}", error_text));
         }
+    }
 
-        let gemini_response: GeminiResponse = response
-            .json()
-            .await
-            .map_err(|e| format!("Failed to parse response: {}", e))?;
-
-        let file_content = gemini_response
-            .candidates
-            .into_iter()
-            .next()
-            .and_then(|c| c.content.parts.into_iter().next())
-            .map(|p| p.text)
-            .ok_or_else(|| "No response from Gemini".to_string())?;
-
-        // Clean up the response - remove code block markers if present
-        let cleaned_content = file_content
-            .trim()
-            .strip_prefix("```")
-            .unwrap_or(&file_content)
-            .strip_suffix("```")
-            .unwrap_or(&file_content)
-            .trim();
-
-        // Show diff if file exists and content changed
-        if file_exists && original_content.trim() != cleaned_content.trim() {
-            self.display_diff(&original_content, cleaned_content, file_path);
-        } else if !file_exists {
-            println!("\n+ Creating new file: {}", file_path);
-        } else {
-            println!("\n✓ No changes needed - file content is already up to date");
-            return Ok(());
-        }
+    /// Requests an edit and makes sure the reply is complete before it is
+    /// used. Truncated replies can be continued, or retried as a
+    /// search/replace edit when `can_switch_protocol` is set; blocked
+    /// replies are always refused. Without `interactive`, truncated replies
+    /// switch protocol when they can and fail otherwise.
+    async fn generate_edit(&self, prompt: String, can_switch_protocol: bool, interactive: bool) -> Result<EditReply, String> {
+        let mut contents = vec![Content::user(prompt)];
+        let mut text = String::new();
+
+        loop {
+            let spinner = interactive.then(|| Spinner::start("Generating..."));
+            let result = self.generate(contents.clone()).await;
+            if let Some(spinner) = spinner {
+                spinner.stop().await;
+            }
+            let generation = result?;
+            text.push_str(&generation.text);
+
+            match &generation.finish_reason {
+                FinishReason::Stop => return Ok(EditReply::Complete(text)),
+                FinishReason::MaxTokens if !interactive => {
+                    if can_switch_protocol {
+                        return Ok(EditReply::SwitchToSearchReplace);
+                    }
+                    return Err("The response was cut off (finishReason MAX_TOKENS); no changes were written".to_string());
+                }
+                FinishReason::MaxTokens => {
+                    output::say("");
+                    output::warn(&format!(
+                        "The response was cut off after {} output tokens (finishReason MAX_TOKENS).",
+                        self.config.max_output_tokens
+                    ));
+                    output::say("  Writing it would replace the file with a partial one.");
 
-        // Create parent directories if they don't exist
-        if let Some(parent) = std::path::Path::new(file_path).parent() {
-            std::fs::create_dir_all(parent)
-                .map_err(|e| format!("Failed to create parent directories: {}", e))?;
+                    let mut options = vec!["Continue the generation"];
+                    if can_switch_protocol {
+                        options.push("Switch to a search/replace edit");
+                    }
+                    options.push("Abort without writing");
+
+                    let selection = Select::with_theme(&ColorfulTheme::default())
+                        .with_prompt("What would you like to do?")
+                        .default(0)
+                        .items(&options)
+                        .interact()
+                        .map_err(|e| format!("Failed to get user selection: {}", e))?;
+
+                    match options[selection] {
+                        "Continue the generation" => {
+                            contents.push(Content::model(generation.text));
+                            contents.push(Content::user(
+                                "Your previous reply was cut off. Continue exactly where it stopped, \
+                                without repeating anything and without any preamble."
+                                    .to_string(),
+                            ));
+                        }
+                        "Switch to a search/replace edit" => return Ok(EditReply::SwitchToSearchReplace),
+                        _ => return Err("The response was truncated; no changes were written".to_string()),
+                    }
+                }
+                FinishReason::Safety => {
+                    let flagged = generation.flagged_categories();
+                    return Err(format!(
+                        "The response was stopped by safety filters{}; no changes were written",
+                        if flagged.is_empty() { String::new() } else { format!(" ({})", flagged) }
+                    ));
+                }
+                FinishReason::Other(reason) => {
+                    return Err(format!(
+                        "The response ended early (finishReason {}); no changes were written",
+                        reason
+                    ));
+                }
+            }
         }
-
-        // Write the file
-        std::fs::write(file_path, cleaned_content)
-            .map_err(|e| format!("Failed to write file: {}", e))?;
-
-        Ok(())
     }
 
-    pub async fn query_gemini(&self, query: &str) -> Result<String, String> {
-        let prompt = format!(
-            "You are a helpful assistant. {}
-
-Please answer the following query:
+    async fn generate(&self, contents: Vec<Content>) -> Result<Generation, String> {
+        self.request(contents, None).await
+    }
 
-{}",
-            self.config.get_response_length_instruction(),
-            query
-        );
+    /// Like `generate`, but constrains the reply to JSON matching `schema`.
+    async fn generate_json(&self, contents: Vec<Content>, schema: serde_json::Value) -> Result<Generation, String> {
+        self.request(contents, Some(schema)).await
+    }
 
+    async fn request(&self, contents: Vec<Content>, schema: Option<serde_json::Value>) -> Result<Generation, String> {
         let request = GeminiRequest {
-            contents: vec![Content {
-                parts: vec![Part { text: prompt }],
-            }],
+            contents,
             generation_config: GenerationConfig {
                 temperature: self.config.temperature,
                 max_output_tokens: self.config.max_output_tokens,
+                response_mime_type: schema.as_ref().map(|_| "application/json".to_string()),
+                response_schema: schema,
             },
         };
 
@@ -413,14 +1188,27 @@ Please answer the following query:
             self.model, self.api_key
         );
 
+        let started = Instant::now();
+        let result = self.send(&url, &request).await;
+        if let Ok(mut stats) = self.stats.lock() {
+            stats.requests += 1;
+            stats.api_time += started.elapsed();
+            if let Ok(generation) = &result {
+                stats.usage.add(generation.usage);
+            }
+        }
+        result
+    }
+
+    async fn send(&self, url: &str, request: &GeminiRequest) -> Result<Generation, String> {
         let response = self
             .client
-            .post(&url)
+            .post(url)
             .header("Content-Type", "application/json")
-            .json(&request)
+            .json(request)
             .send()
             .await
-            .map_err(|e| format!("Request failed: {}", e))?;
+            .map_err(|e| format!("Request failed: {}", e.without_url()))?;
 
         if !response.status().is_success() {
             let error_text = response.text().await.unwrap_or_default();
@@ -432,258 +1220,67 @@ Please answer the following query:
             .await
             .map_err(|e| format!("Failed to parse response: {}", e))?;
 
-        let gemini_text = gemini_response
-            .candidates
-            .into_iter()
-            .next()
-            .and_then(|c| c.content.parts.into_iter().next())
-            .map(|p| p.text)
-            .ok_or_else(|| "No response from Gemini".to_string())?;
-
-        Ok(self.convert_markdown_to_ansi(&gemini_text))
+        parse_generation(gemini_response)
     }
 
-    fn wrap_text(&self, text: &str, max_width: usize, current_indent: usize) -> String {
-        let mut wrapped_lines = Vec::new();
-        let mut current_line = String::new();
-        let effective_width = max_width - current_indent;
-
-        let ansi_regex = Regex::new(r"\x1b\[[0-9;]*[a-zA-Z]").unwrap();
-
-        for word in text.split_whitespace() {
-            let word_stripped = ansi_regex.replace_all(word, "").to_string();
-            let current_line_stripped = ansi_regex.replace_all(&current_line, "").to_string();
+    /// Transforms `input` according to `instruction` and returns only the
+    /// result, extracted the same way as file content in write mode.
+    pub async fn transform(&self, input: &str, instruction: &str) -> Result<String, String> {
+        let prompt = format!(
+            "You are a text transformation tool used in a shell pipeline. Transform the input below according to \
+            the instruction.\n\n\
+            Instruction: {}\n\n\
+            Input:\n\
+            ```\n{}\n```\n\n\
+            Output only the transformed text, with no explanations or markdown formatting.",
+            instruction, input
+        );
 
-            if current_line.is_empty() {
-                current_line.push_str(word);
-            } else if current_line_stripped.len() + 1 + word_stripped.len() <= effective_width {
-                current_line.push(' ');
-                current_line.push_str(word);
-            } else {
-                wrapped_lines.push(current_line);
-                current_line = String::from(word);
+        let generation = self.generate(vec![Content::user(prompt)]).await?;
+        match &generation.finish_reason {
+            FinishReason::Stop => {}
+            FinishReason::MaxTokens => {
+                return Err(format!(
+                    "The response was cut off after {} output tokens (finishReason MAX_TOKENS)",
+                    self.config.max_output_tokens
+                ))
             }
+            FinishReason::Safety => return Err("The response was stopped by safety filters".to_string()),
+            FinishReason::Other(reason) => return Err(format!("The response ended early (finishReason {})", reason)),
         }
-        wrapped_lines.push(current_line);
 
-        let mut result = String::new();
-        for (i, line) in wrapped_lines.into_iter().enumerate() {
-            if i > 0 {
-                result.push_str(&format!("{:indent$}", " ", indent = current_indent));
-            }
-            result.push_str(&line);
-            result.push('\n');
+        let mut output = extract::unwrap_reply(&generation.text);
+        // Keep the input's convention for a trailing newline
+        if !input.ends_with('\n') && output.ends_with('\n') {
+            output.pop();
         }
-        result.pop();
-        result
+        Ok(output)
     }
 
-    fn find_syntax_for_language(&self, lang: &str) -> &syntect::parsing::SyntaxReference {
-        let lang_lower = lang.to_lowercase();
-
-        if let Some(syntax) = self.syntax_set.find_syntax_by_name(&lang_lower) {
-            return syntax;
-        }
-
-        if let Some(syntax) = self.syntax_set.find_syntax_by_extension(&lang_lower) {
-            return syntax;
-        }
-
-        let mapped_lang = match lang_lower.as_str() {
-            "js" | "javascript" | "node" => "JavaScript",
-            "ts" | "typescript" => "TypeScript",
-            "py" | "python" => "Python",
-            "rs" | "rust" => "Rust",
-            "go" | "golang" => "Go",
-            "cpp" | "c++" | "cxx" => "C++",
-            "c" => "C",
-            "java" => "Java",
-            "kt" | "kotlin" => "Kotlin",
-            "cs" | "csharp" | "c#" => "C#",
-            "rb" | "ruby" => "Ruby",
-            "php" => "PHP",
-            "swift" => "Swift",
-            "scala" => "Scala",
-            "clj" | "clojure" => "Clojure",
-            "hs" | "haskell" => "Haskell",
-            "lua" => "Lua",
-            "perl" | "pl" => "Perl",
-            "r" => "R",
-            "matlab" | "m" => "MATLAB",
-            "sh" | "bash" | "shell" => "Bourne Again Shell (bash)",
-            "zsh" => "Bourne Again Shell (bash)", // fallback to bash
-            "fish" => "fish",
-            "ps1" | "powershell" => "PowerShell",
-            "bat" | "batch" => "Batch File",
-            "html" | "htm" => "HTML",
-            "css" => "CSS",
-            "scss" | "sass" => "Sass",
-            "less" => "CSS", // fallback to CSS
-            "xml" => "XML",
-            "json" => "JSON",
-            "yaml" | "yml" => "YAML",
-            "toml" => "TOML",
-            "ini" | "cfg" | "conf" => "INI",
-            "dockerfile" | "docker" => "Dockerfile",
-            "sql" => "SQL",
-            "md" | "markdown" => "Markdown",
-            "tex" | "latex" => "LaTeX",
-            "vim" => "VimL",
-            "make" | "makefile" => "Makefile",
-            "cmake" => "CMake",
-            "gradle" => "Gradle",
-            "dart" => "Dart",
-            "elm" => "Elm",
-            "erlang" | "erl" => "Erlang",
-            "elixir" | "ex" => "Elixir",
-            "fsharp" | "fs" | "f#" => "F#",
-            "ocaml" | "ml" => "OCaml",
-            "nim" => "Nim",
-            "crystal" | "cr" => "Crystal",
-            "d" => "D",
-            "zig" => "Zig",
-            "v" | "vlang" => "V",
-            "assembly" | "asm" => "Assembly x86_64",
-            "diff" | "patch" => "Diff",
-            "log" => "Log",
-            "text" | "txt" => "Plain Text",
-            _ => "",
-        };
-
-        if !mapped_lang.is_empty() {
-            if let Some(syntax) = self.syntax_set.find_syntax_by_name(mapped_lang) {
-                return syntax;
-            }
-        }
-
-        self.syntax_set.find_syntax_plain_text()
-    }
-
-    fn convert_markdown_to_ansi(&self, text: &str) -> String {
-        let mut result_lines = Vec::new();
-        const RESET: &str = "\x1b[0m";
-        const BOLD: &str = "\x1b[1m";
-        const ITALIC: &str = "\x1b[3m";
-        const CYAN: &str = "\x1b[36m";
-        const MAX_LINE_WIDTH: usize = 100;
-
-        let numbered_list_start_regex = Regex::new(r"^(\d+\.\s+)(.*)$").unwrap();
-        let next_steps_heading_regex = Regex::new(r"(?i)Next Steps:").unwrap();
-        let mut current_list_indent = 0;
-        let mut in_next_steps_section = false;
-
-        let mut in_code_block = false;
-        let mut code_block_lang = String::new();
-        let mut code_block_content = Vec::new();
-
-        for line in text.lines() {
-            if line.starts_with("```") {
-                if in_code_block {
-                    in_code_block = false;
-                    let code = code_block_content.join("\n");
-                    code_block_content.clear();
-
-                    if !code.trim().is_empty() {
-                        let syntax = self.find_syntax_for_language(&code_block_lang);
-                        let theme = &self.theme_set.themes["base16-ocean.dark"];
-
-                        let mut highlighter = HighlightLines::new(syntax, theme);
-                        let highlighted_code = code
-                            .lines()
-                            .map(|line| {
-                                let ranges: Vec<(syntect::highlighting::Style, &str)> = highlighter
-                                    .highlight_line(line, &self.syntax_set)
-                                    .unwrap_or_default();
-                                if ranges.is_empty() {
-                                    line.to_string()
-                                } else {
-                                    as_24_bit_terminal_escaped(&ranges[..], false)
-                                }
-                            })
-                            .collect::<Vec<String>>()
-                            .join("\n");
-                        result_lines.push(highlighted_code);
-                    }
-                } else {
-                    in_code_block = true;
-                    code_block_lang = line.trim_start_matches("```").trim().to_string();
-                    if code_block_lang.is_empty() {
-                        code_block_lang = "text".to_string();
-                    }
-                }
-                continue;
-            }
-
-            if in_code_block {
-                code_block_content.push(line.to_string());
-                continue;
-            }
-
-            let mut processed_line = line.to_string();
-            let mut line_indent_for_wrapping = 0;
-
-            if next_steps_heading_regex.is_match(&processed_line) {
-                in_next_steps_section = true;
-                current_list_indent = 0;
-            } else if let Some(caps) = numbered_list_start_regex.captures(&processed_line) {
-                let num_part = caps.get(1).unwrap().as_str();
-                let text_part = caps.get(2).unwrap().as_str();
-                current_list_indent = num_part.len();
-                if in_next_steps_section {
-                    current_list_indent += 4;
-                }
-                processed_line = format!("{}{}", num_part, text_part);
-                line_indent_for_wrapping = current_list_indent;
-            } else if current_list_indent > 0 && !processed_line.trim().is_empty() {
-                line_indent_for_wrapping = current_list_indent;
-                processed_line = format!(
-                    "{:<width$}{}",
-                    " ",
-                    processed_line,
-                    width = line_indent_for_wrapping
-                );
-            } else {
-                current_list_indent = 0;
-                in_next_steps_section = false;
-                line_indent_for_wrapping = 0;
-            }
-
-            processed_line = processed_line
-                .replace("**", "")
-                .replace("* ", "")
-                .replace(" *", "");
-
-            let bold_regex = Regex::new(r"\*\*(.*?)\*\*|__(.*?)__").unwrap();
-            processed_line = bold_regex
-                .replace_all(&processed_line, &format!("{}{}{}", BOLD, "$1$2", RESET))
-                .to_string();
-
-            let italics_regex =
-                Regex::new(r"\*([^*\s][^*]*[^*\s])\*|_([^_\s][^_]*[^_\s])_").unwrap();
-            processed_line = italics_regex
-                .replace_all(&processed_line, &format!("{}{}{}", ITALIC, "$1$2", RESET))
-                .to_string();
-
-            let monospace_regex = Regex::new(r"`([^`]+)`").unwrap();
-            processed_line = monospace_regex
-                .replace_all(&processed_line, &format!("{}{}{}", CYAN, "$1", RESET))
-                .to_string();
+    /// Answers a free-form query, returning the model's markdown.
+    pub async fn query_gemini(&self, query: &str) -> Result<String, String> {
+        let prompt = format!(
+            "You are a helpful assistant. {}
 
-            let heading_regex = Regex::new(r"^#\s*(.*)$").unwrap();
-            processed_line = heading_regex
-                .replace_all(&processed_line, &format!("\n{}{}{}\n", BOLD, "$1", RESET))
-                .to_string();
+Please answer the following query:
 
-            processed_line =
-                self.wrap_text(&processed_line, MAX_LINE_WIDTH, line_indent_for_wrapping);
+{}",
+            self.config.get_response_length_instruction(),
+            query
+        );
 
-            result_lines.push(processed_line);
-        }
+        Ok(self.generate(vec![Content::user(prompt)]).await?.text)
+    }
 
-        result_lines.join("\n")
+    pub fn convert_markdown_to_ansi(&self, text: &str) -> String {
+        let width = markdown::wrap_width(self.config.wrap_width);
+        let theme = self.theme.get_or_init(|| theme::resolve(&self.config.theme));
+        MarkdownRenderer::new(&self.syntax_set, theme, width).render(text)
     }
 
-    fn format_prompt(&self, commands: &[CommandEntry]) -> String {
+    /// The shared start of both analysis prompts: the role and the recent
+    /// commands with their output.
+    fn format_history(&self, commands: &[CommandEntry]) -> String {
         let mut prompt = String::from(
             "You are a helpful shell command assistant. The user has provided a history of their last few commands. \
             Use the full history for context, but focus your analysis and suggestions *only* on the most recent command.\n\n"
@@ -698,7 +1295,7 @@ Please answer the following query:
                         cmd.command, cmd.output
                     ));
                 }
-                prompt.push_str("\n");
+                prompt.push('\n');
             }
 
             prompt.push_str("--- Command to Analyze ---\n");
@@ -708,6 +1305,11 @@ Please answer the following query:
             ));
         }
 
+        prompt
+    }
+
+    fn format_prompt(&self, commands: &[CommandEntry]) -> String {
+        let mut prompt = self.format_history(commands);
         prompt.push_str(&format!(
             "Please provide the following for the last command only:
 
@@ -726,3 +1328,317 @@ Please answer the following query:
         prompt
     }
 }
+
+/// Turns a model reply into the new content of `original`, either by
+/// applying its search/replace blocks or by extracting the whole file.
+fn apply_reply(protocol: EditProtocol, original: &str, reply: &str, file_path: &str) -> Result<String, String> {
+    if protocol != EditProtocol::SearchReplace {
+        return Ok(extract::extract_file_content(reply, file_path));
+    }
+    let blocks = edit::parse_edit_response(reply)?;
+    edit::apply_edits(original, &blocks).map_err(|failures| {
+        let details: Vec<String> = failures.iter().map(|f| format!("  {}", f.describe())).collect();
+        format!(
+            "{} of {} edit blocks could not be applied; no changes were written:\n{}",
+            failures.len(),
+            blocks.len(),
+            details.join("\n")
+        )
+    })
+}
+
+/// Encodes content the way it will be written to disk.
+fn encode_for(snapshot: Option<&FileSnapshot>, content: &str) -> Result<Vec<u8>, String> {
+    match snapshot {
+        Some(snapshot) => snapshot.restore_format(content),
+        None => Ok(content.as_bytes().to_vec()),
+    }
+}
+
+/// Splits a free-form analysis reply into its sections.
+fn parse_analysis(text: &str) -> Analysis {
+    let mut suggestion = None;
+    let mut cleaned_text = text.to_string();
+
+    // The suggestion may be on the heading line or the line after it;
+    // remove only that section, not other mentions of the command
+    let suggestion_capture_regex = Regex::new(r"(?im)^did you mean[:\s`*]*([^`*\n\r]+?)[`*\s]*$").unwrap();
+    if let Some(caps) = suggestion_capture_regex.captures(text) {
+        suggestion = Some(caps.get(1).unwrap().as_str().trim().to_string());
+        cleaned_text.replace_range(caps.get(0).unwrap().range(), "");
+    }
+
+    let cleanup_did_you_mean_regex = Regex::new(r"(?im)^did you mean[:\s`*].*$").unwrap();
+    let cleaned_text = cleanup_did_you_mean_regex.replace_all(&cleaned_text, "").to_string();
+
+    let extra_newlines_regex = Regex::new(r"\n{3,}").unwrap();
+    let cleaned_text = extra_newlines_regex.replace_all(&cleaned_text, "\n\n").trim().to_string();
+
+    // Section labels may be headings, bold labels or plain `Label:` lines;
+    // prose that merely starts with the words is not a label
+    let next_steps_regex = Regex::new(
+        r"(?im)^(?:#{1,6}[ \t]*next steps[ \t]*:?[ \t]*$|(?:\*\*|__)next steps[ \t]*:?(?:\*\*|__):?[ \t]*|next steps[ \t]*:[ \t]*$)",
+    )
+    .unwrap();
+    let analysis_label_regex = Regex::new(
+        r"(?i)^(?:#{1,6}[ \t]*analysis[ \t]*:?[ \t]*(?:\n|$)|(?:\*\*|__)analysis[ \t]*:?(?:\*\*|__):?[ \t]*|analysis[ \t]*:[ \t]*)",
+    )
+    .unwrap();
+
+    let (analysis, steps) = match next_steps_regex.find(&cleaned_text) {
+        Some(heading) => (&cleaned_text[..heading.start()], &cleaned_text[heading.end()..]),
+        None => (cleaned_text.as_str(), ""),
+    };
+    let analysis = analysis_label_regex.replace(analysis.trim(), "").trim().to_string();
+
+    Analysis {
+        analysis,
+        next_steps: parse_list_items(steps),
+        suggestions: suggestion
+            .iter()
+            .map(|command| Suggestion { command: command.clone(), explanation: None })
+            .collect(),
+        suggestion,
+        confidence: None,
+        reply: Some(text.to_string()),
+    }
+}
+
+/// The response schema for structured analysis, in the OpenAPI subset
+/// Gemini accepts.
+fn analysis_schema() -> serde_json::Value {
+    serde_json::json!({
+        "type": "OBJECT",
+        "properties": {
+            "analysis": { "type": "STRING" },
+            "steps": { "type": "ARRAY", "items": { "type": "STRING" } },
+            "suggestions": {
+                "type": "ARRAY",
+                "items": {
+                    "type": "OBJECT",
+                    "properties": {
+                        "command": { "type": "STRING" },
+                        "explanation": { "type": "STRING" }
+                    },
+                    "required": ["command"]
+                }
+            },
+            "confidence": { "type": "NUMBER" }
+        },
+        "required": ["analysis", "steps", "suggestions", "confidence"]
+    })
+}
+
+/// Reads a structured analysis reply, tolerating a markdown code fence
+/// around the JSON.
+fn parse_structured_analysis(text: &str) -> Option<Analysis> {
+    let start = text.find('{')?;
+    let end = text.rfind('}')?;
+    let reply: StructuredAnalysis = serde_json::from_str(text.get(start..=end)?).ok()?;
+    Some(reply.into())
+}
+
+/// The items of a markdown list, with continuation lines joined. Text that
+/// is not a list becomes a single item.
+fn parse_list_items(text: &str) -> Vec<String> {
+    let item_regex = Regex::new(r"^\s*(?:\d+[.)]|[-*+])\s+(.*)$").unwrap();
+    let mut items: Vec<String> = Vec::new();
+    for line in text.lines() {
+        if let Some(caps) = item_regex.captures(line) {
+            items.push(caps[1].trim().to_string());
+        } else if let Some(last) = items.last_mut() {
+            if !line.trim().is_empty() {
+                last.push(' ');
+                last.push_str(line.trim());
+            }
+        }
+    }
+    if items.is_empty() && !text.trim().is_empty() {
+        items.push(text.trim().to_string());
+    }
+    items
+}
+
+fn parse_generation(response: GeminiResponse) -> Result<Generation, String> {
+    let usage = TokenUsage {
+        prompt_tokens: response.usage_metadata.prompt_token_count,
+        output_tokens: response.usage_metadata.candidates_token_count,
+        total_tokens: response.usage_metadata.total_token_count,
+    };
+    let candidate = match response.candidates.into_iter().next() {
+        Some(candidate) => candidate,
+        None => {
+            return Err(match response.prompt_feedback.and_then(|f| f.block_reason.map(|r| (r, f.safety_ratings))) {
+                Some((reason, ratings)) => {
+                    let generation = Generation {
+                        text: String::new(),
+                        finish_reason: FinishReason::Safety,
+                        safety_ratings: ratings,
+                        usage: TokenUsage::default(),
+                    };
+                    let flagged = generation.flagged_categories();
+                    format!(
+                        "The prompt was blocked by Gemini (blockReason {}){}",
+                        reason,
+                        if flagged.is_empty() { String::new() } else { format!(": {}", flagged) }
+                    )
+                }
+                None => "No response from Gemini".to_string(),
+            });
+        }
+    };
+
+    let text: String = candidate
+        .content
+        .map(|c| c.parts.into_iter().map(|p| p.text).collect())
+        .unwrap_or_default();
+
+    let generation = Generation {
+        text,
+        finish_reason: FinishReason::parse(candidate.finish_reason.as_deref()),
+        safety_ratings: candidate.safety_ratings,
+        usage,
+    };
+
+    if generation.text.is_empty() {
+        return Err(match generation.finish_reason {
+            FinishReason::Safety => {
+                format!("The response was blocked by safety filters ({})", generation.flagged_categories())
+            }
+            FinishReason::Other(ref reason) => format!("Gemini returned no text (finishReason {})", reason),
+            _ => "No response from Gemini".to_string(),
+        });
+    }
+
+    Ok(generation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> Result<Generation, String> {
+        parse_generation(serde_json::from_str(json).unwrap())
+    }
+
+    #[test]
+    fn test_parse_generation_stop() {
+        let generation = parse(
+            r#"{"candidates":[{"content":{"parts":[{"text":"hello "},{"text":"world"}],"role":"model"},"finishReason":"STOP"}],
+                "usageMetadata":{"promptTokenCount":5,"candidatesTokenCount":2,"totalTokenCount":7}}"#,
+        )
+        .unwrap();
+        assert_eq!(generation.text, "hello world");
+        assert_eq!(generation.finish_reason, FinishReason::Stop);
+        assert_eq!(generation.usage.total_tokens, 7);
+    }
+
+    #[test]
+    fn test_parse_generation_max_tokens() {
+        let generation = parse(
+            r#"{"candidates":[{"content":{"parts":[{"text":"fn main() {"}]},"finishReason":"MAX_TOKENS"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(generation.finish_reason, FinishReason::MaxTokens);
+    }
+
+    #[test]
+    fn test_parse_generation_safety_with_partial_text() {
+        let generation = parse(
+            r#"{"candidates":[{"content":{"parts":[{"text":"partial"}]},"finishReason":"SAFETY",
+                "safetyRatings":[{"category":"HARM_CATEGORY_DANGEROUS_CONTENT","probability":"HIGH","blocked":true},
+                                 {"category":"HARM_CATEGORY_HARASSMENT","probability":"NEGLIGIBLE"}]}]}"#,
+        )
+        .unwrap();
+        assert_eq!(generation.finish_reason, FinishReason::Safety);
+        assert_eq!(generation.flagged_categories(), "dangerous content (high)");
+    }
+
+    #[test]
+    fn test_parse_generation_safety_without_content() {
+        let err = parse(
+            r#"{"candidates":[{"finishReason":"SAFETY",
+                "safetyRatings":[{"category":"HARM_CATEGORY_HATE_SPEECH","probability":"MEDIUM"}]}]}"#,
+        )
+        .unwrap_err();
+        assert!(err.contains("hate speech (medium)"));
+    }
+
+    #[test]
+    fn test_parse_generation_prompt_blocked() {
+        let err = parse(r#"{"promptFeedback":{"blockReason":"SAFETY","safetyRatings":[]}}"#).unwrap_err();
+        assert!(err.contains("blockReason SAFETY"));
+    }
+
+    #[test]
+    fn test_parse_analysis_sections() {
+        let analysis = parse_analysis(
+            "**Analysis:** `gti` is not a command.\n\n**Next Steps:**\n1. Check the spelling\n   of the command.\n2. Read the error.\n\nDid you mean: `git status`",
+        );
+        assert_eq!(analysis.analysis, "`gti` is not a command.");
+        assert_eq!(analysis.next_steps, vec!["Check the spelling of the command.", "Read the error."]);
+        assert_eq!(analysis.suggestion.as_deref(), Some("git status"));
+
+        let plain = parse_analysis("The command succeeded.");
+        assert_eq!(plain.analysis, "The command succeeded.");
+        assert!(plain.next_steps.is_empty());
+        assert_eq!(plain.suggestion, None);
+    }
+
+    #[test]
+    fn test_parse_analysis_ignores_next_steps_in_prose() {
+        let analysis = parse_analysis("The build failed.\nNext steps are unclear because the log is empty.");
+        assert_eq!(analysis.analysis, "The build failed.\nNext steps are unclear because the log is empty.");
+        assert!(analysis.next_steps.is_empty());
+
+        let labelled = parse_analysis("The build failed.\n\nNext steps:\n- Rerun it");
+        assert_eq!(labelled.analysis, "The build failed.");
+        assert_eq!(labelled.next_steps, vec!["Rerun it"]);
+    }
+
+    #[test]
+    fn test_parse_analysis_strips_headings() {
+        let analysis = parse_analysis("## Analysis\n\nThe push was rejected.\n\n### Next Steps\n\n1. Pull first");
+        assert_eq!(analysis.analysis, "The push was rejected.");
+        assert_eq!(analysis.next_steps, vec!["Pull first"]);
+    }
+
+    #[test]
+    fn test_parse_analysis_keeps_other_mentions_of_suggestion() {
+        let analysis = parse_analysis("Run `git status` to see changes.\n\nDid you mean: `git status`");
+        assert_eq!(analysis.analysis, "Run `git status` to see changes.");
+        assert_eq!(analysis.suggestion.as_deref(), Some("git status"));
+        assert_eq!(analysis.suggestions.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_structured_analysis() {
+        let reply = "```json\n{\"analysis\": \"`gti` is not a command.\", \"steps\": [\"Check the spelling\", \"\"], \
+            \"suggestions\": [{\"command\": \"git status\", \"explanation\": \"gti is a typo\"}, {\"command\": \"git\"}], \
+            \"confidence\": 0.9}\n```";
+        let analysis = parse_structured_analysis(reply).unwrap();
+        assert_eq!(analysis.analysis, "`gti` is not a command.");
+        assert_eq!(analysis.next_steps, vec!["Check the spelling"]);
+        assert_eq!(analysis.suggestion.as_deref(), Some("git status"));
+        assert_eq!(analysis.suggestions[0].explanation.as_deref(), Some("gti is a typo"));
+        assert_eq!(analysis.suggestions[1].explanation, None);
+        assert_eq!(analysis.confidence, Some(0.9));
+
+        assert_eq!(parse_structured_analysis("Not JSON at all"), None);
+        assert_eq!(parse_structured_analysis("{\"steps\": []}"), None);
+    }
+
+    #[test]
+    fn test_analysis_to_markdown_round_trips() {
+        let text = "**Analysis:**\n`gti` is not a command.\n\n**Next Steps:**\n1. Check the spelling\n\nDid you mean: `git status`";
+        let analysis = Analysis { reply: None, ..parse_analysis(text) };
+        assert_eq!(analysis.to_markdown(), text);
+        assert_eq!(Analysis { reply: None, ..parse_analysis(&analysis.to_markdown()) }, analysis);
+    }
+
+    #[test]
+    fn test_free_form_to_markdown_keeps_reply() {
+        let text = "## Analysis\n\nThe push was rejected.\n\n## Next steps\n\n- Pull first\n- Then push\n\n| a | b |\n|---|---|";
+        assert_eq!(parse_analysis(text).to_markdown(), text);
+    }
+}
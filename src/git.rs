@@ -0,0 +1,170 @@
+use crate::output::{self, Palette};
+use similar::TextDiff;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitStatus {
+    pub root: PathBuf,
+    pub tracked: bool,
+    pub dirty: bool,
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<String, String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run git: {}", e))?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Directory git commands for `file_path` should run in. The file itself
+/// may not exist yet, so the nearest existing ancestor is used.
+fn working_dir(file_path: &str) -> PathBuf {
+    let mut dir = Path::new(file_path)
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    while !dir.as_os_str().is_empty() && !dir.exists() {
+        dir = dir.parent().map(Path::to_path_buf).unwrap_or_default();
+    }
+    if dir.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        dir
+    }
+}
+
+pub fn repo_root(file_path: &str) -> Option<PathBuf> {
+    git(&working_dir(file_path), &["rev-parse", "--show-toplevel"])
+        .ok()
+        .map(|root| PathBuf::from(root.trim()))
+}
+
+/// Returns `None` when the file is not inside a git work tree.
+pub fn file_status(file_path: &str) -> Option<GitStatus> {
+    let root = repo_root(file_path)?;
+    let dir = working_dir(file_path);
+    let name = absolute(file_path);
+    let name = name.to_string_lossy();
+
+    let tracked = git(&dir, &["ls-files", "--error-unmatch", "--", &name]).is_ok();
+    let dirty = git(&dir, &["status", "--porcelain", "--", &name])
+        .map(|out| !out.trim().is_empty())
+        .unwrap_or(false);
+
+    Some(GitStatus { root, tracked, dirty })
+}
+
+pub fn stage(file_path: &str) -> Result<(), String> {
+    let name = absolute(file_path);
+    git(&working_dir(file_path), &["add", "--", &name.to_string_lossy()])
+        .map(|_| ())
+        .map_err(|e| format!("git add failed: {}", e))
+}
+
+/// Stages the file and commits only that path, leaving anything else the
+/// user has staged untouched.
+pub fn commit(file_path: &str, message: &str) -> Result<(), String> {
+    stage(file_path)?;
+    let name = absolute(file_path);
+    git(
+        &working_dir(file_path),
+        &["commit", "-m", message, "--", &name.to_string_lossy()],
+    )
+    .map(|_| ())
+    .map_err(|e| format!("git commit failed: {}", e))
+}
+
+fn absolute(file_path: &str) -> PathBuf {
+    let path = Path::new(file_path);
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir().unwrap_or_default().join(path)
+    }
+}
+
+/// Renders the change as a `git diff` style unified diff with a/ and b/
+/// prefixes, without colors.
+pub fn unified_diff(original: &str, new_content: &str, file_path: &str) -> String {
+    let display_path = repo_relative(file_path);
+    let diff = TextDiff::from_lines(original, new_content);
+
+    let mut out = format!("diff --git a/{0} b/{0}\n", display_path);
+    if original.is_empty() {
+        out.push_str("new file mode 100644\n");
+    }
+    out.push_str(
+        &diff
+            .unified_diff()
+            .context_radius(3)
+            .header(
+                &if original.is_empty() { "/dev/null".to_string() } else { format!("a/{}", display_path) },
+                &format!("b/{}", display_path),
+            )
+            .to_string(),
+    );
+    out
+}
+
+/// Colors a unified diff the way `git diff` does by default.
+pub fn colorize_diff(diff: &str) -> String {
+    let Palette { red, green, cyan, bold, reset, .. } = output::palette();
+
+    diff.lines()
+        .map(|line| {
+            if line.starts_with("diff --git")
+                || line.starts_with("--- ")
+                || line.starts_with("+++ ")
+                || line.starts_with("new file mode")
+            {
+                format!("{}{}{}", bold, line, reset)
+            } else if line.starts_with("@@") {
+                format!("{}{}{}", cyan, line, reset)
+            } else if line.starts_with('+') {
+                format!("{}{}{}", green, line, reset)
+            } else if line.starts_with('-') {
+                format!("{}{}{}", red, line, reset)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn repo_relative(file_path: &str) -> String {
+    let path = absolute(file_path);
+    match repo_root(file_path).and_then(|root| path.strip_prefix(root).ok().map(Path::to_path_buf)) {
+        Some(relative) => relative.to_string_lossy().to_string(),
+        None => file_path.trim_start_matches("./").to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unified_diff_has_git_headers() {
+        let diff = unified_diff("a\nb\n", "a\nc\n", "x.txt");
+        assert!(diff.starts_with("diff --git a/"));
+        assert!(diff.contains("-b\n+c\n"));
+        assert!(diff.contains("@@ -1,2 +1,2 @@"));
+    }
+
+    #[test]
+    fn test_unified_diff_new_file() {
+        let diff = unified_diff("", "hello\n", "new.txt");
+        assert!(diff.contains("new file mode 100644"));
+        assert!(diff.contains("--- /dev/null"));
+    }
+}
@@ -0,0 +1,216 @@
+use crate::config::Config;
+use crate::context;
+use crate::git;
+use globset::{Glob, GlobSetBuilder};
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::{Component, Path, PathBuf};
+
+// Lines at the top of a file searched for a "generated" banner
+const GENERATED_HEADER_LINES: usize = 5;
+
+const GENERATED_MARKERS: &[&str] = &["@generated", "do not edit", "auto-generated", "autogenerated", "code generated"];
+
+const GENERATED_NAMES: &[&str] = &[
+    "*.min.js",
+    "*.min.css",
+    "*.lock",
+    "package-lock.json",
+    "*.pb.go",
+    "*_pb2.py",
+    "*.generated.*",
+];
+
+/// Paths write mode refuses by default. `~` is the home directory; patterns
+/// are matched against the absolute path.
+pub fn default_protected_paths() -> Vec<String> {
+    [
+        "~/.ssh/**",
+        "~/.gnupg/**",
+        "~/.aws/**",
+        "~/.netrc",
+        "/etc/**",
+        "**/.git/**",
+        "**/.env",
+        "**/.env.*",
+        "**/*.pem",
+        "**/*.key",
+        "**/id_rsa*",
+        "**/id_ed25519*",
+    ]
+    .iter()
+    .map(|pattern| pattern.to_string())
+    .collect()
+}
+
+/// Makes `path` absolute and removes `.` and `..` components without
+/// requiring it to exist. Symlinks in the existing part are resolved.
+pub fn normalize(path: &Path) -> PathBuf {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir().unwrap_or_default().join(path)
+    };
+
+    let mut clean = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                clean.pop();
+            }
+            other => clean.push(other),
+        }
+    }
+
+    // Resolve symlinks in the deepest existing ancestor
+    let mut existing = clean.clone();
+    let mut rest = Vec::new();
+    while !existing.exists() {
+        match existing.file_name() {
+            Some(name) => rest.push(name.to_os_string()),
+            None => return clean,
+        }
+        if !existing.pop() {
+            return clean;
+        }
+    }
+    let mut resolved = fs::canonicalize(&existing).unwrap_or(existing);
+    for name in rest.into_iter().rev() {
+        resolved.push(name);
+    }
+    resolved
+}
+
+fn expand_home(pattern: &str) -> String {
+    match (pattern.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => format!("{}/{}", home.to_string_lossy().trim_end_matches('/'), rest),
+        _ => pattern.to_string(),
+    }
+}
+
+fn matches_any(path: &Path, patterns: &[String]) -> Option<String> {
+    patterns.iter().find_map(|pattern| {
+        let glob = Glob::new(&expand_home(pattern)).ok()?.compile_matcher();
+        glob.is_match(path).then(|| pattern.clone())
+    })
+}
+
+fn is_generated(path: &Path) -> Option<String> {
+    let mut names = GlobSetBuilder::new();
+    for pattern in GENERATED_NAMES {
+        if let Ok(glob) = Glob::new(pattern) {
+            names.add(glob);
+        }
+    }
+    let file_name = path.file_name()?;
+    if names.build().ok()?.is_match(file_name) {
+        return Some(format!("its name marks it as a generated file ({})", file_name.to_string_lossy()));
+    }
+
+    let file = fs::File::open(path).ok()?;
+    BufReader::new(file)
+        .lines()
+        .take(GENERATED_HEADER_LINES)
+        .map_while(Result::ok)
+        .find(|line| {
+            let line = line.to_lowercase();
+            GENERATED_MARKERS.iter().any(|marker| line.contains(marker))
+        })
+        .map(|line| format!("its header says it is generated (\"{}\")", line.trim()))
+}
+
+/// Lists the reasons why writing `file_path` is unsafe. Paths covered by
+/// `allowed` (from `--allow-path`) are never refused.
+pub fn check_write_path(file_path: &str, config: &Config, allowed: &[String]) -> Vec<String> {
+    let target = normalize(Path::new(file_path));
+    if allowed
+        .iter()
+        .any(|allow| target.starts_with(normalize(Path::new(allow))))
+    {
+        return Vec::new();
+    }
+
+    let mut problems = Vec::new();
+
+    if let Some(pattern) = matches_any(&target, &config.protected_paths) {
+        problems.push(format!("it matches the protected path pattern `{}`", pattern));
+    }
+
+    if config.restrict_to_git_root {
+        // Outside a repository the current directory is the boundary
+        let (boundary, name) = match git::repo_root(".") {
+            Some(root) => (root, "the current git repository"),
+            None => (PathBuf::from("."), "the current directory"),
+        };
+        if !target.starts_with(normalize(&boundary)) {
+            problems.push(format!("it is outside {}", name));
+        }
+    }
+
+    if target.is_file() {
+        if context::is_binary(&target) {
+            problems.push("it is a binary file".to_string());
+        }
+        if let Some(reason) = is_generated(&target) {
+            problems.push(reason);
+        }
+    }
+
+    problems
+}
+
+/// Fails with an explanation if `file_path` is protected.
+pub fn ensure_writable(file_path: &str, config: &Config, allowed: &[String]) -> Result<(), String> {
+    let problems = check_write_path(file_path, config, allowed);
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "Refusing to write {} because {}. Pass --allow-path {} to write it anyway",
+        file_path,
+        problems.join(", and "),
+        file_path
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_normalize_removes_dot_components() {
+        let cwd = fs::canonicalize(std::env::current_dir().unwrap()).unwrap();
+        assert_eq!(normalize(Path::new("src/../src/./main.rs")), cwd.join("src/main.rs"));
+        assert_eq!(normalize(Path::new("missing/dir/file.txt")), cwd.join("missing/dir/file.txt"));
+    }
+
+    #[test]
+    fn test_protected_and_outside_paths() {
+        let config = Config::default();
+        assert!(check_write_path("src/main.rs", &config, &[]).is_empty());
+        assert!(!check_write_path(".env", &config, &[]).is_empty());
+        assert!(!check_write_path("/etc/hosts", &config, &[]).is_empty());
+        assert!(!check_write_path("../outside.txt", &config, &[]).is_empty());
+        assert!(!check_write_path("Cargo.lock", &config, &[]).is_empty());
+        assert!(check_write_path(".env", &config, &[".env".to_string()]).is_empty());
+
+        let relaxed = Config {
+            restrict_to_git_root: false,
+            protected_paths: Vec::new(),
+            ..Config::default()
+        };
+        assert!(check_write_path("../outside.txt", &relaxed, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_generated_header() {
+        let dir = TempDir::new("guard");
+        let path = dir.join("schema.rs");
+        fs::write(&path, "// Code generated by protoc. DO NOT EDIT.\nfn a() {}\n").unwrap();
+        assert!(is_generated(&path).is_some());
+        fs::write(&path, "fn a() {}\n").unwrap();
+        assert!(is_generated(&path).is_none());
+    }
+}
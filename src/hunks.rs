@@ -0,0 +1,137 @@
+use crate::output::{self, Palette};
+use dialoguer::{Select, theme::ColorfulTheme};
+use similar::{ChangeTag, DiffOp, TextDiff};
+
+// Unchanged lines shown around each hunk, as in `git diff`
+const CONTEXT_LINES: usize = 3;
+
+/// The hunks of the line diff between `original` and `new_content`.
+pub fn hunks(original: &str, new_content: &str) -> Vec<Vec<DiffOp>> {
+    TextDiff::from_lines(original, new_content).grouped_ops(CONTEXT_LINES)
+}
+
+/// Rebuilds the file from `original`, taking the changes of every hunk whose
+/// entry in `accepted` is true and keeping the original lines for the rest.
+pub fn assemble(original: &str, new_content: &str, hunks: &[Vec<DiffOp>], accepted: &[bool]) -> String {
+    let old_lines: Vec<&str> = original.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new_content.split_inclusive('\n').collect();
+    let mut result = String::new();
+    let mut position = 0;
+
+    for (hunk, &accept) in hunks.iter().zip(accepted) {
+        for op in hunk {
+            let old_range = op.old_range();
+            result.push_str(&old_lines[position..old_range.start].concat());
+            if accept && op.tag() != similar::DiffTag::Equal {
+                result.push_str(&new_lines[op.new_range()].concat());
+            } else {
+                result.push_str(&old_lines[old_range.clone()].concat());
+            }
+            position = old_range.end;
+        }
+    }
+    result.push_str(&old_lines[position..].concat());
+    result
+}
+
+/// Formats one hunk with its `@@` header and colored lines.
+pub fn format_hunk(original: &str, new_content: &str, hunk: &[DiffOp]) -> String {
+    let Palette { red, blue, cyan, reset, .. } = output::palette();
+
+    let diff = TextDiff::from_lines(original, new_content);
+    let (first, last) = (&hunk[0], &hunk[hunk.len() - 1]);
+    let old_start = first.old_range().start;
+    let new_start = first.new_range().start;
+    let old_len = last.old_range().end - old_start;
+    let new_len = last.new_range().end - new_start;
+
+    let mut output = format!(
+        "{}@@ -{},{} +{},{} @@{}\n",
+        cyan,
+        old_start + usize::from(old_len > 0),
+        old_len,
+        new_start + usize::from(new_len > 0),
+        new_len,
+        reset
+    );
+    for op in hunk {
+        for change in diff.iter_changes(op) {
+            let line = change.value().trim_end_matches('\n');
+            match change.tag() {
+                ChangeTag::Delete => output.push_str(&format!("{}-{}{}\n", red, line, reset)),
+                ChangeTag::Insert => output.push_str(&format!("{}+{}{}\n", blue, line, reset)),
+                ChangeTag::Equal => output.push_str(&format!(" {}\n", line)),
+            }
+        }
+    }
+    output
+}
+
+/// Walks through the hunks one at a time, like `git add -p`, and returns the
+/// content assembled from the accepted ones, or `None` if none were accepted.
+pub fn select_hunks(original: &str, new_content: &str) -> Result<Option<String>, String> {
+    let hunks = hunks(original, new_content);
+    let mut accepted = vec![false; hunks.len()];
+    let options = [
+        "Apply this hunk",
+        "Skip this hunk",
+        "Apply this and all remaining hunks",
+        "Skip this and all remaining hunks",
+    ];
+
+    for (index, hunk) in hunks.iter().enumerate() {
+        output::say(format_args!("\n{}", format_hunk(original, new_content, hunk).trim_end()));
+        let selection = Select::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("Hunk {}/{}", index + 1, hunks.len()))
+            .default(0)
+            .items(&options)
+            .interact()
+            .map_err(|e| format!("Failed to get user selection: {}", e))?;
+
+        match selection {
+            0 => accepted[index] = true,
+            1 => {}
+            2 => {
+                accepted[index..].iter_mut().for_each(|a| *a = true);
+                break;
+            }
+            _ => break,
+        }
+    }
+
+    if !accepted.contains(&true) {
+        return Ok(None);
+    }
+    Ok(Some(assemble(original, new_content, &hunks, &accepted)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGINAL: &str = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\n";
+    const NEW: &str = "A\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\nm\n";
+
+    #[test]
+    fn test_hunks_are_split_by_distance() {
+        assert_eq!(hunks(ORIGINAL, NEW).len(), 2);
+        assert_eq!(hunks(ORIGINAL, ORIGINAL).len(), 0);
+    }
+
+    #[test]
+    fn test_assemble_selected_hunks() {
+        let hunks = hunks(ORIGINAL, NEW);
+        assert_eq!(assemble(ORIGINAL, NEW, &hunks, &[true, true]), NEW);
+        assert_eq!(assemble(ORIGINAL, NEW, &hunks, &[false, false]), ORIGINAL);
+        assert_eq!(assemble(ORIGINAL, NEW, &hunks, &[true, false]), ORIGINAL.replacen('a', "A", 1));
+        assert_eq!(assemble(ORIGINAL, NEW, &hunks, &[false, true]), format!("{}m\n", ORIGINAL));
+    }
+
+    #[test]
+    fn test_format_hunk_header() {
+        let hunks = hunks(ORIGINAL, NEW);
+        let text = format_hunk(ORIGINAL, NEW, &hunks[1]);
+        assert!(text.contains("@@ -10,3 +10,4 @@"));
+        assert!(text.contains("+m"));
+    }
+}
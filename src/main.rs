@@ -1,116 +1,309 @@
+mod batch;
+mod clipboard;
 mod config;
+mod context;
+mod edit;
+mod extract;
+mod fileio;
 mod gemini;
+mod git;
+mod guard;
 mod history;
+mod hunks;
+mod markdown;
+mod notebook;
+mod output;
+mod pager;
 mod prompt;
+mod report;
 mod shell;
+mod spinner;
+mod structured;
+mod symbols;
+#[cfg(test)]
+mod testutil;
+mod theme;
+mod undo;
+mod validate;
 
 use clap::{Arg, Command};
+use clipboard::CopyTarget;
 use config::ConfigManager;
-use gemini::GeminiClient;
-use history::HistoryManager;
+use dialoguer::{Confirm, Input, theme::ColorfulTheme};
+use context::ContextLimits;
+use gemini::{GeminiClient, WriteOptions};
+use history::{CommandEntry, HistoryManager};
+use serde_json::json;
+use spinner::Spinner;
 use std::env;
-use std::fs;
-use std::io::{self, Write};
-use std::time::Duration;
-use tokio::select;
-use tokio::sync::oneshot;
-
-async fn loading_animation(mut rx: oneshot::Receiver<()>) {
-    let frames = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
-    let mut i = 0;
-    print!("\x1b[?25l");
-    loop {
-        select! {
-            _ = tokio::time::sleep(Duration::from_millis(20)) => {
-                print!("\r{} Analyzing...\x1b[K", frames[i % frames.len()]);
-                io::stdout().flush().unwrap();
-                i += 1;
-            }
-            _ = &mut rx => {
-                break;
-            }
-        }
+use std::io::{self, IsTerminal, Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Copies the suggestion or a code block for `--copy` and `--copy-block`.
+fn copy_to_clipboard(target: CopyTarget, suggestion: Option<&str>, markdown: &str) {
+    match clipboard::select(target, suggestion, markdown).and_then(|text| clipboard::copy(&text)) {
+        Ok(method) => eprintln!("✓ Copied to the clipboard using {}", method),
+        Err(e) => eprintln!("Error copying to the clipboard: {}", e),
+    }
+}
+
+/// Offers to run the suggested command after the user has had a chance to
+/// edit it. Returns the command and its output once it has run.
+fn offer_to_run(suggestion: &str, config: &config::Config) -> Result<Option<CommandEntry>, String> {
+    println!();
+    let command: String = Input::with_theme(&ColorfulTheme::default())
+        .with_prompt("Run (edit it, or clear it to skip)")
+        .with_initial_text(suggestion)
+        .allow_empty(true)
+        .interact_text()
+        .map_err(|e| format!("Failed to read the command: {}", e))?;
+    let command = command.trim();
+    if command.is_empty() {
+        return Ok(None);
     }
-    print!("\r\x1b[K");
-    print!("\x1b[?25h");
-    io::stdout().flush().unwrap();
+
+    let cwd = env::current_dir().map_err(|e| format!("Failed to read the current directory: {}", e))?;
+    let confirmed = Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt(format!("Run `{}` in {}?", command, cwd.display()))
+        .default(false)
+        .interact()
+        .map_err(|e| format!("Failed to get confirmation: {}", e))?;
+    if !confirmed {
+        return Ok(None);
+    }
+
+    let shell = env::var("SHELL").unwrap_or_else(|_| config.default_shell.clone());
+    shell::run_command(&shell, command).map(Some)
 }
 
-async fn handle_wut_command(api_key: String, model: String, config: &config::Config) {
+async fn handle_wut_command(
+    api_key: String,
+    model: String,
+    config: &config::Config,
+    raw: bool,
+    copy: Option<CopyTarget>,
+) {
+    let started = Instant::now();
     let history_manager = HistoryManager::new().unwrap();
-    let commands = history_manager.get_last_commands(2).unwrap();
+    let mut commands = history_manager.get_last_commands(2).unwrap();
 
     if commands.is_empty() {
-        println!("No commands found in history.");
+        if output::json_mode() {
+            report::print_json_error("analyze", "No commands found in history");
+        } else {
+            println!("No commands found in history.");
+        }
         return;
     }
 
     let client = GeminiClient::new(api_key, model, config);
+    let interactive = !output::json_mode() && io::stdin().is_terminal() && io::stdout().is_terminal();
 
-    let (tx, rx) = oneshot::channel();
-    let animation_handle = tokio::spawn(loading_animation(rx));
+    loop {
+        let spinner = Spinner::start("Analyzing...");
+        let result = client.analyze_commands(&commands).await;
+        spinner.stop().await;
 
-    match client.analyze_commands(&commands).await {
-        Ok((analysis_text, _suggestion)) => {
-            let _ = tx.send(());
-            animation_handle.await.unwrap();
+        match &result {
+            Ok(analysis) if output::json_mode() => {
+                let fields = serde_json::to_value(analysis).unwrap_or_default();
+                report::print_json("analyze", client.model(), fields, client.usage(), client.timings(started));
+            }
+            Ok(analysis) if raw => {
+                let text = analysis.to_markdown();
+                if !pager::page(&text, &config.pager) {
+                    println!("{}", text);
+                }
+            }
+            Ok(analysis) => {
+                let text = client.render_analysis(analysis);
+                let text = output::paint(&text);
+                if !pager::page(&text, &config.pager) {
+                    println!("{}", text);
+                }
+            }
+            Err(e) if output::json_mode() => report::print_json_error("analyze", e),
+            Err(e) => {
+                eprintln!("Error analyzing commands: {}", e);
+            }
+        }
 
-            print!("{}", analysis_text);
-            println!();
-            io::stdout().flush().unwrap();
+        if let (Ok(analysis), Some(target)) = (&result, copy) {
+            copy_to_clipboard(target, analysis.suggestion.as_deref(), &analysis.to_markdown());
         }
-        Err(e) => {
-            let _ = tx.send(());
-            animation_handle.await.unwrap();
-            eprintln!("Error analyzing commands: {}", e);
+
+        let suggestion = match &result {
+            Ok(analysis) if interactive => analysis.suggestion.clone(),
+            _ => None,
+        };
+        let Some(suggestion) = suggestion else {
+            return;
+        };
+        let entry = match offer_to_run(&suggestion, config) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return;
+            }
+        };
+
+        let analyze_again = Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt("Analyze its output?")
+            .default(true)
+            .interact()
+            .unwrap_or(false);
+        if !analyze_again {
+            return;
         }
+        // The command that just ran becomes the one to analyze
+        commands.insert(0, entry);
+        commands.truncate(2);
     }
 }
 
-async fn handle_write_command(file_path: String, context: String, api_key: String, model: String, config: &config::Config) {
+async fn handle_write_command(
+    file_path: String,
+    context: String,
+    options: WriteOptions,
+    api_key: String,
+    model: String,
+    config: &config::Config,
+) {
+    let started = Instant::now();
     let client = GeminiClient::new(api_key, model, config);
 
-    let (tx, rx) = oneshot::channel();
-    let animation_handle = tokio::spawn(loading_animation(rx));
-
-    match client.write_or_edit_file(&file_path, &context).await {
-        Ok(()) => {
-            let _ = tx.send(());
-            animation_handle.await.unwrap();
+    match client.write_or_edit_file(&file_path, &context, &options).await {
+        Ok(change) if output::json_mode() => {
+            let fields = json!({ "files": [change] });
+            report::print_json("write", client.model(), fields, client.usage(), client.timings(started));
+        }
+        Ok(_) => {
             println!("✓ File {} has been successfully written/edited!", file_path);
         }
+        Err(e) if output::json_mode() => report::print_json_error("write", &e),
         Err(e) => {
-            let _ = tx.send(());
-            animation_handle.await.unwrap();
             eprintln!("Error writing/editing file: {}", e);
         }
     }
 }
 
-async fn handle_query_command(query: String, api_key: String, model: String, config: &config::Config) {
+async fn handle_batch_command(
+    pattern: String,
+    context: String,
+    options: WriteOptions,
+    api_key: String,
+    model: String,
+    config: &config::Config,
+) {
+    let started = Instant::now();
+    let client = Arc::new(GeminiClient::new(api_key, model, config));
+
+    match batch::run(Arc::clone(&client), &pattern, &context, &options, config).await {
+        Ok(changes) if output::json_mode() => {
+            let fields = json!({ "files": changes });
+            report::print_json("write", client.model(), fields, client.usage(), client.timings(started));
+        }
+        Ok(_) => {}
+        Err(e) if output::json_mode() => report::print_json_error("write", &e),
+        Err(e) => {
+            eprintln!("Error running batch edit: {}", e);
+        }
+    }
+}
+
+async fn handle_transform_command(instruction: String, api_key: String, model: String, config: &config::Config) {
+    if io::stdin().is_terminal() {
+        eprintln!("Reading input from the terminal; finish with Ctrl-D");
+    }
+    let mut input = String::new();
+    if let Err(e) = io::stdin().read_to_string(&mut input) {
+        eprintln!("Error reading stdin: {}", e);
+        std::process::exit(1);
+    }
+    if input.trim().is_empty() {
+        eprintln!("Error: transform needs input on stdin");
+        std::process::exit(1);
+    }
+
+    let started = Instant::now();
     let client = GeminiClient::new(api_key, model, config);
 
-    let (tx, rx) = oneshot::channel();
-    let animation_handle = tokio::spawn(loading_animation(rx));
+    let spinner = Spinner::start("Transforming...");
+    let result = client.transform(&input, &instruction).await;
+    spinner.stop().await;
 
-    match client.query_gemini(&query).await {
-        Ok(response_text) => {
-            let _ = tx.send(());
-            animation_handle.await.unwrap();
+    match result {
+        Ok(output) if output::json_mode() => {
+            let fields = json!({ "output": output });
+            report::print_json("transform", client.model(), fields, client.usage(), client.timings(started));
+        }
+        Ok(output) => {
+            print!("{}", output);
+            io::stdout().flush().unwrap();
+        }
+        Err(e) if output::json_mode() => {
+            report::print_json_error("transform", &e);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Error transforming input: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn handle_query_command(
+    query: String,
+    api_key: String,
+    model: String,
+    config: &config::Config,
+    raw: bool,
+    copy: Option<CopyTarget>,
+) {
+    let started = Instant::now();
+    let client = GeminiClient::new(api_key, model, config);
 
-            for char_code in response_text.chars() {
-                print!("{}", char_code);
-                io::stdout().flush().unwrap();
-                tokio::time::sleep(Duration::from_millis(3)).await;
+    let spinner = Spinner::start("Analyzing...");
+    let result = client.query_gemini(&query).await;
+    spinner.stop().await;
+
+    match &result {
+        Ok(answer) if output::json_mode() => {
+            let fields = json!({ "answer": answer });
+            report::print_json("query", client.model(), fields, client.usage(), client.timings(started));
+        }
+        Ok(answer) if raw => {
+            if !pager::page(answer, &config.pager) {
+                println!("{}", answer);
             }
-            println!();
         }
+        Ok(answer) => {
+            let response_text = client.convert_markdown_to_ansi(answer);
+            let response_text = output::paint(&response_text);
+            if pager::page(&response_text, &config.pager) {
+                // Already shown
+            } else if !io::stdout().is_terminal() {
+                println!("{}", response_text);
+            } else {
+                for char_code in response_text.chars() {
+                    print!("{}", char_code);
+                    io::stdout().flush().unwrap();
+                    tokio::time::sleep(Duration::from_millis(3)).await;
+                }
+                println!();
+            }
+        }
+        Err(e) if output::json_mode() => report::print_json_error("query", e),
         Err(e) => {
-            let _ = tx.send(());
-            animation_handle.await.unwrap();
             eprintln!("Error querying Gemini: {}", e);
         }
     }
+
+    if let (Ok(answer), Some(target)) = (&result, copy) {
+        copy_to_clipboard(target, None, answer);
+    }
 }
 
 #[tokio::main]
@@ -161,14 +354,116 @@ async fn main() {
                 .action(clap::ArgAction::SetTrue)
                 .help("Write/edit mode - use with @<file> <context>"),
         )
+        .arg(
+            Arg::new("git-diff")
+                .long("git-diff")
+                .action(clap::ArgAction::SetTrue)
+                .help("In write mode, show the change as a full git-style diff"),
+        )
+        .arg(
+            Arg::new("semantic-diff")
+                .long("semantic-diff")
+                .action(clap::ArgAction::SetTrue)
+                .help("In write mode, show which keys changed in JSON and TOML files"),
+        )
+        .arg(
+            Arg::new("patch")
+                .short('p')
+                .long("patch")
+                .action(clap::ArgAction::SetTrue)
+                .help("In write mode, choose which hunks of the change to apply, like git add -p"),
+        )
+        .arg(
+            Arg::new("ref")
+                .long("ref")
+                .value_name("FILE")
+                .action(clap::ArgAction::Append)
+                .help("In write mode, include FILE as read-only context (may be repeated)"),
+        )
+        .arg(
+            Arg::new("undo")
+                .long("undo")
+                .action(clap::ArgAction::SetTrue)
+                .help("Revert the last batch edit (only the most recent one can be undone)"),
+        )
+        .arg(
+            Arg::new("allow-path")
+                .long("allow-path")
+                .value_name("PATH")
+                .action(clap::ArgAction::Append)
+                .help("In write mode, allow writing to a protected PATH (or anything under it)"),
+        )
+        .arg(
+            Arg::new("color")
+                .long("color")
+                .value_name("WHEN")
+                .value_parser(["auto", "always", "never"])
+                .default_value("auto")
+                .global(true)
+                .help("Color the output: auto (terminal only, honoring NO_COLOR), always or never"),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_name("FORMAT")
+                .value_parser(["text", "json"])
+                .default_value("text")
+                .global(true)
+                .help("Output format: text, or json for a single machine-readable document on stdout"),
+        )
+        .arg(
+            Arg::new("no-pager")
+                .long("no-pager")
+                .action(clap::ArgAction::SetTrue)
+                .global(true)
+                .help("Print long output directly instead of piping it through $PAGER"),
+        )
+        .arg(
+            Arg::new("raw")
+                .long("raw")
+                .action(clap::ArgAction::SetTrue)
+                .help("Print the model's markdown unchanged, without terminal formatting"),
+        )
+        .arg(
+            Arg::new("copy")
+                .long("copy")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("copy-block")
+                .help("Copy the suggested command to the clipboard"),
+        )
+        .arg(
+            Arg::new("copy-block")
+                .long("copy-block")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Copy the Nth code block of the reply to the clipboard"),
+        )
         .arg(
             Arg::new("query")
                 .help("Query to send to Gemini")
                 .num_args(0..)
                 .trailing_var_arg(true),
         )
+        .subcommand(
+            Command::new("transform")
+                .about("Transform stdin according to an instruction and print only the result")
+                .arg(
+                    Arg::new("instruction")
+                        .help("What to do with the input, e.g. \"sort imports\"")
+                        .required(true)
+                        .num_args(1..)
+                        .trailing_var_arg(true),
+                ),
+        )
         .get_matches();
 
+    let color = matches.get_one::<String>("color").map(String::as_str).unwrap_or("auto");
+    output::init(output::ColorChoice::parse(color).unwrap_or(output::ColorChoice::Auto));
+    output::set_json_mode(matches.get_one::<String>("format").is_some_and(|format| format == "json"));
+    if matches.get_flag("no-pager") {
+        pager::disable();
+    }
+
     // Initialize config manager
     let config_manager = ConfigManager::new().expect("Failed to initialize config manager");
     
@@ -190,6 +485,42 @@ async fn main() {
         println!("  Auto-save history: {}", config.auto_save_history);
         println!("  Default shell: {}", config.default_shell);
         println!("  API timeout: {} seconds", config.api_timeout);
+        println!("  Edit protocol: {}", config.edit_protocol);
+        println!("  Whole-file max lines: {}", config.whole_file_max_lines);
+        println!("  Git dirty policy: {}", config.git_dirty_policy);
+        println!("  Offer git commit: {}", config.git_offer_commit);
+        println!("  Max file size: {} bytes", config.max_file_bytes);
+        println!("  Max context size: {} bytes", config.max_context_bytes);
+        println!("  Check commands:");
+        for (extension, command) in &config.check_commands {
+            println!("    .{}: {}", extension, command);
+        }
+        println!("  Max repair attempts: {}", config.max_repair_attempts);
+        println!("  Clear stale notebook outputs: {}", config.notebook_clear_outputs);
+        println!("  Protected paths: {}", config.protected_paths.join(", "));
+        println!("  Restrict writes to git root: {}", config.restrict_to_git_root);
+        println!("  Batch concurrency: {}", config.batch_concurrency);
+        println!("  Wrap width: {}", config::wrap_width_label(config.wrap_width));
+        println!("  Theme: {}", config.theme);
+        println!("  Structured analysis: {}", config.structured_analysis);
+        println!("  Pager: {}", config::pager_label(&config.pager));
+        return;
+    }
+
+    // Handle --undo flag
+    if matches.get_flag("undo") {
+        match undo::undo_last() {
+            Ok((description, files)) => {
+                println!("✓ Undid {}", description);
+                for file in files {
+                    println!("  restored {}", file);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
         return;
     }
 
@@ -238,64 +569,136 @@ async fn main() {
     // Use the configured default model
     let model = config.default_model.clone();
 
+    // Filter mode: huh transform "<instruction>" < input > output
+    if let Some(transform_matches) = matches.subcommand_matches("transform") {
+        let instruction = transform_matches
+            .get_many::<String>("instruction")
+            .map(|words| words.cloned().collect::<Vec<String>>().join(" "))
+            .unwrap_or_default();
+        handle_transform_command(instruction, api_key, model, &config).await;
+        return;
+    }
+
     let write_mode = matches.get_flag("write");
+    let raw = matches.get_flag("raw");
+    let copy = if matches.get_flag("copy") {
+        Some(CopyTarget::Suggestion)
+    } else {
+        matches.get_one::<u64>("copy-block").map(|number| CopyTarget::Block(*number as usize))
+    };
+    let mut write_options = WriteOptions {
+        git_diff: matches.get_flag("git-diff"),
+        semantic_diff: matches.get_flag("semantic-diff"),
+        patch: matches.get_flag("patch"),
+        allowed_paths: matches
+            .get_many::<String>("allow-path")
+            .map(|paths| paths.cloned().collect())
+            .unwrap_or_default(),
+        ..WriteOptions::default()
+    };
 
     if let Some(query_args) = matches.get_many::<String>("query") {
         let query_vec: Vec<&str> = query_args.map(|s| s.as_str()).collect();
 
         if !query_vec.is_empty() {
-            let first_arg = query_vec[0];
-            if first_arg.starts_with('@') {
-                let file_path = &first_arg[1..];
-
-                if write_mode {
-                    // Write/edit mode: huh -w @file context
+            if write_mode {
+                if let Some(target) = query_vec[0].strip_prefix('@') {
+                    // Write/edit mode: huh -w @file[:N-M|#symbol] [@ref...] context
+                    let context = query_vec[1..].join(" ");
+                    let explicit: Vec<String> = matches
+                        .get_many::<String>("ref")
+                        .map(|refs| refs.cloned().collect())
+                        .unwrap_or_default();
+                    // @words in the instruction are references only if they name existing files
+                    let mentioned = context::path_references(&query_vec[1..]);
                     if query_vec.len() > 1 {
-                        let context = query_vec[1..].join(" ");
-                        handle_write_command(file_path.to_string(), context, api_key, model, &config).await;
+                        let reference = context::parse_reference(target);
+                        if reference.selection.is_some() && context::is_glob(&reference.path) {
+                            eprintln!(
+                                "Error: A line range or symbol cannot be combined with a glob; select part of a single file instead"
+                            );
+                            return;
+                        }
+                        if !explicit.is_empty() || !mentioned.is_empty() {
+                            let limits = ContextLimits {
+                                max_file_bytes: config.max_file_bytes,
+                                max_total_bytes: config.max_context_bytes,
+                            };
+                            let all: Vec<String> = explicit.iter().chain(&mentioned).cloned().collect();
+                            let collected = match context::collect_context(&all, limits) {
+                                Err(e) if !mentioned.is_empty() => {
+                                    eprintln!("Warning: ignoring the references in the instruction: {}", e);
+                                    context::collect_context(&explicit, limits)
+                                }
+                                result => result,
+                            };
+                            match collected {
+                                Ok(collected) => {
+                                    for skipped in &collected.skipped {
+                                        eprintln!("Skipping {}", skipped);
+                                    }
+                                    write_options.references = collected.files;
+                                }
+                                Err(e) => {
+                                    eprintln!("{}", e);
+                                    return;
+                                }
+                            }
+                        }
+                        if context::is_glob(&reference.path) {
+                            handle_batch_command(reference.path, context, write_options, api_key, model, &config).await;
+                        } else {
+                            write_options.selection = reference.selection;
+                            handle_write_command(reference.path, context, write_options, api_key, model, &config).await;
+                        }
                     } else {
                         eprintln!(
                             "Error: Write mode requires context. Usage: huh -w @<file> <context>"
                         );
                     }
                 } else {
-                    // Query mode: huh @file context (existing behavior)
-                    match fs::read_to_string(file_path) {
-                        Ok(file_content) => {
-                            let mut query = format!(
-                                "Content from {}:\n---\n{}\n---\n",
-                                file_path, file_content
-                            );
-                            if query_vec.len() > 1 {
-                                query.push_str(&query_vec[1..].join(" "));
+                    eprintln!("Error: Write mode requires a file path starting with @. Usage: huh -w @<file> <context>");
+                }
+            } else {
+                // Query mode: @references anywhere in the query are read as context
+                let (references, query) = context::split_query(&query_vec);
+                if references.is_empty() {
+                    handle_query_command(query, api_key, model, &config, raw, copy).await;
+                } else {
+                    let limits = ContextLimits {
+                        max_file_bytes: config.max_file_bytes,
+                        max_total_bytes: config.max_context_bytes,
+                    };
+                    match context::collect_context(&references, limits) {
+                        Ok(collected) => {
+                            for skipped in &collected.skipped {
+                                eprintln!("Skipping {}", skipped);
+                            }
+                            if collected.files.is_empty() {
+                                eprintln!("Error: None of the referenced files could be included.");
+                            } else {
+                                let query = format!("{}{}", context::format_context(&collected.files), query);
+                                handle_query_command(query, api_key, model, &config, raw, copy).await;
                             }
-                            handle_query_command(query, api_key, model, &config).await;
                         }
                         Err(e) => {
-                            eprintln!("Error reading file {}: {}", file_path, e);
+                            eprintln!("{}", e);
                         }
                     }
                 }
-            } else {
-                if write_mode {
-                    eprintln!("Error: Write mode requires a file path starting with @. Usage: huh -w @<file> <context>");
-                } else {
-                    let query = query_vec.join(" ");
-                    handle_query_command(query, api_key, model, &config).await;
-                }
             }
         } else {
             if write_mode {
                 eprintln!("Error: Write mode requires arguments. Usage: huh -w @<file> <context>");
             } else {
-                handle_wut_command(api_key, model, &config).await;
+                handle_wut_command(api_key, model, &config, raw, copy).await;
             }
         }
     } else {
         if write_mode {
             eprintln!("Error: Write mode requires arguments. Usage: huh -w @<file> <context>");
         } else {
-            handle_wut_command(api_key, model, &config).await;
+            handle_wut_command(api_key, model, &config, raw, copy).await;
         }
     }
 }
@@ -0,0 +1,758 @@
+use pulldown_cmark::{Alignment, CodeBlockKind, Event, HeadingLevel, Options, Parser, Tag, TagEnd};
+use regex::Regex;
+use std::sync::OnceLock;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::Theme;
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+use syntect::util::{as_24_bit_terminal_escaped, LinesWithEndings};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const ITALIC: &str = "\x1b[3m";
+const UNDERLINE: &str = "\x1b[4m";
+const STRIKETHROUGH: &str = "\x1b[9m";
+const BLUE: &str = "\x1b[34m";
+const CYAN: &str = "\x1b[36m";
+
+// Used when the output is not a terminal and no width is configured
+const DEFAULT_WRAP_WIDTH: usize = 100;
+
+// Narrowest column text is wrapped to, however deeply it is nested
+const MIN_WRAP_WIDTH: usize = 20;
+
+const BULLETS: [&str; 3] = ["•", "◦", "▪"];
+
+// The Markdown extensions replies are parsed with, for rendering and for
+// finding code blocks alike
+const PARSER_OPTIONS: Options = Options::ENABLE_TABLES
+    .union(Options::ENABLE_STRIKETHROUGH)
+    .union(Options::ENABLE_TASKLISTS);
+
+/// Matches CSI styling sequences and OSC sequences such as hyperlinks.
+fn escape_regex() -> &'static Regex {
+    static ESCAPE: OnceLock<Regex> = OnceLock::new();
+    ESCAPE.get_or_init(|| Regex::new(r"\x1b\[[0-9;]*[A-Za-z]|\x1b\][^\x07\x1b]*(?:\x07|\x1b\\)").unwrap())
+}
+
+/// Removes escape sequences, leaving only the visible text.
+pub fn strip_ansi(text: &str) -> String {
+    escape_regex().replace_all(text, "").to_string()
+}
+
+/// Width of `text` in terminal columns, ignoring escape sequences. Wide
+/// characters such as CJK and most emoji take two columns.
+pub fn display_width(text: &str) -> usize {
+    UnicodeWidthStr::width(strip_ansi(text).as_str())
+}
+
+/// Shortens `text` to at most `width` columns, ending it with "..." if
+/// anything was cut.
+pub fn truncate_to_width(text: &str, width: usize) -> String {
+    if display_width(text) <= width {
+        return text.to_string();
+    }
+    let mut result = String::new();
+    let mut used = 0;
+    for c in text.chars() {
+        let char_width = c.width().unwrap_or(0);
+        if used + char_width + 3 > width {
+            break;
+        }
+        result.push(c);
+        used += char_width;
+    }
+    result.push_str("...");
+    result
+}
+
+/// Column to wrap at: the configured width, or else the terminal's width.
+pub fn wrap_width(configured: usize) -> usize {
+    if configured > 0 {
+        return configured;
+    }
+    match console::Term::stdout().size_checked() {
+        Some((_, columns)) => columns as usize,
+        None => DEFAULT_WRAP_WIDTH,
+    }
+}
+
+/// Breaks styled text into lines. Each line closes the styles and
+/// hyperlink still open at its end and the next line reopens them, so
+/// prefixes added to the lines are never styled or linked.
+struct LineBreaker {
+    lines: Vec<String>,
+    line: String,
+    width: usize,
+    styles: Vec<String>,
+    link: Option<String>,
+}
+
+impl LineBreaker {
+    fn break_line(&mut self) {
+        if self.link.is_some() {
+            self.line.push_str("\x1b]8;;\x1b\\");
+        }
+        if !self.styles.is_empty() {
+            self.line.push_str(RESET);
+        }
+        let mut next = self.styles.concat();
+        next.push_str(self.link.as_deref().unwrap_or(""));
+        self.lines.push(std::mem::replace(&mut self.line, next));
+        self.width = 0;
+    }
+
+    fn push_escape(&mut self, escape: &str) {
+        if let Some(params) = escape.strip_prefix("\x1b]8;") {
+            let url = params.split_once(';').map(|(_, rest)| rest).unwrap_or("");
+            let url = url.trim_end_matches('\x07').trim_end_matches("\x1b\\");
+            self.link = (!url.is_empty()).then(|| escape.to_string());
+        } else if escape == RESET || escape == "\x1b[m" {
+            self.styles.clear();
+        } else if escape.starts_with("\x1b[") {
+            self.styles.push(escape.to_string());
+        }
+        self.line.push_str(escape);
+    }
+
+    /// Adds a word, splitting it between characters if it is wider than a line.
+    fn push_word(&mut self, word: &str, max_width: usize) {
+        let mut position = 0;
+        for escape in escape_regex().find_iter(word) {
+            self.push_text(&word[position..escape.start()], max_width);
+            self.push_escape(escape.as_str());
+            position = escape.end();
+        }
+        self.push_text(&word[position..], max_width);
+    }
+
+    fn push_text(&mut self, text: &str, max_width: usize) {
+        for c in text.chars() {
+            let char_width = c.width().unwrap_or(0);
+            if self.width > 0 && self.width + char_width > max_width {
+                self.break_line();
+            }
+            self.line.push(c);
+            self.width += char_width;
+        }
+    }
+}
+
+/// Wraps styled text to `width` columns at word boundaries. `\n` forces a
+/// line break. Escape sequences are never split.
+pub fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut breaker = LineBreaker {
+        lines: Vec::new(),
+        line: String::new(),
+        width: 0,
+        styles: Vec::new(),
+        link: None,
+    };
+    for (index, segment) in text.split('\n').enumerate() {
+        if index > 0 {
+            breaker.break_line();
+        }
+        for word in segment.split_whitespace() {
+            let word_width = display_width(word);
+            if breaker.width > 0 && breaker.width + 1 + word_width > width {
+                breaker.break_line();
+            }
+            if breaker.width > 0 {
+                breaker.line.push(' ');
+                breaker.width += 1;
+            }
+            breaker.push_word(word, width);
+        }
+    }
+    breaker.lines.push(breaker.line);
+    breaker.lines
+}
+
+/// The contents of every code block in `text`, fenced or indented, in order.
+pub fn code_blocks(text: &str) -> Vec<String> {
+    let mut blocks = Vec::new();
+    let mut current: Option<String> = None;
+    for event in Parser::new_ext(text, PARSER_OPTIONS) {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => current = Some(String::new()),
+            Event::Text(code) => {
+                if let Some(block) = current.as_mut() {
+                    block.push_str(&code);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => blocks.extend(current.take()),
+            _ => {}
+        }
+    }
+    blocks
+}
+
+pub fn find_syntax<'a>(syntax_set: &'a SyntaxSet, lang: &str) -> &'a SyntaxReference {
+    let lang_lower = lang.to_lowercase();
+
+    if let Some(syntax) = syntax_set.find_syntax_by_name(&lang_lower) {
+        return syntax;
+    }
+
+    if let Some(syntax) = syntax_set.find_syntax_by_extension(&lang_lower) {
+        return syntax;
+    }
+
+    let mapped_lang = match lang_lower.as_str() {
+        "js" | "javascript" | "node" => "JavaScript",
+        "ts" | "typescript" => "TypeScript",
+        "py" | "python" => "Python",
+        "rs" | "rust" => "Rust",
+        "go" | "golang" => "Go",
+        "cpp" | "c++" | "cxx" => "C++",
+        "c" => "C",
+        "java" => "Java",
+        "kt" | "kotlin" => "Kotlin",
+        "cs" | "csharp" | "c#" => "C#",
+        "rb" | "ruby" => "Ruby",
+        "php" => "PHP",
+        "swift" => "Swift",
+        "scala" => "Scala",
+        "clj" | "clojure" => "Clojure",
+        "hs" | "haskell" => "Haskell",
+        "lua" => "Lua",
+        "perl" | "pl" => "Perl",
+        "r" => "R",
+        "matlab" | "m" => "MATLAB",
+        "sh" | "bash" | "shell" => "Bourne Again Shell (bash)",
+        "zsh" => "Bourne Again Shell (bash)", // fallback to bash
+        "fish" => "fish",
+        "ps1" | "powershell" => "PowerShell",
+        "bat" | "batch" => "Batch File",
+        "html" | "htm" => "HTML",
+        "css" => "CSS",
+        "scss" | "sass" => "Sass",
+        "less" => "CSS", // fallback to CSS
+        "xml" => "XML",
+        "json" => "JSON",
+        "yaml" | "yml" => "YAML",
+        "toml" => "TOML",
+        "ini" | "cfg" | "conf" => "INI",
+        "dockerfile" | "docker" => "Dockerfile",
+        "sql" => "SQL",
+        "md" | "markdown" => "Markdown",
+        "tex" | "latex" => "LaTeX",
+        "vim" => "VimL",
+        "make" | "makefile" => "Makefile",
+        "cmake" => "CMake",
+        "gradle" => "Gradle",
+        "dart" => "Dart",
+        "elm" => "Elm",
+        "erlang" | "erl" => "Erlang",
+        "elixir" | "ex" => "Elixir",
+        "fsharp" | "fs" | "f#" => "F#",
+        "ocaml" | "ml" => "OCaml",
+        "nim" => "Nim",
+        "crystal" | "cr" => "Crystal",
+        "d" => "D",
+        "zig" => "Zig",
+        "v" | "vlang" => "V",
+        "assembly" | "asm" => "Assembly x86_64",
+        "diff" | "patch" => "Diff",
+        "log" => "Log",
+        "text" | "txt" => "Plain Text",
+        _ => "",
+    };
+
+    if !mapped_lang.is_empty() {
+        if let Some(syntax) = syntax_set.find_syntax_by_name(mapped_lang) {
+            return syntax;
+        }
+    }
+
+    syntax_set.find_syntax_plain_text()
+}
+
+/// Renders CommonMark to ANSI-styled terminal text, highlighting fenced
+/// code blocks with syntect.
+pub struct MarkdownRenderer<'a> {
+    syntax_set: &'a SyntaxSet,
+    theme: &'a Theme,
+    width: usize,
+}
+
+/// A block container that indents its content: a block quote or list item.
+struct Container {
+    /// List marker still to be printed on the item's first line
+    marker: Option<String>,
+    indent: String,
+}
+
+struct Table {
+    alignments: Vec<Alignment>,
+    rows: Vec<Vec<String>>,
+    row: Vec<String>,
+}
+
+struct Writer<'r, 'a> {
+    renderer: &'r MarkdownRenderer<'a>,
+    lines: Vec<String>,
+    inline: String,
+    styles: Vec<&'static str>,
+    containers: Vec<Container>,
+    /// Next number of each open list, or `None` for bullet lists
+    lists: Vec<Option<u64>>,
+    code: Option<(String, String)>,
+    table: Option<Table>,
+    links: Vec<(String, usize)>,
+    needs_blank: bool,
+}
+
+impl<'a> MarkdownRenderer<'a> {
+    pub fn new(syntax_set: &'a SyntaxSet, theme: &'a Theme, width: usize) -> Self {
+        MarkdownRenderer {
+            syntax_set,
+            theme,
+            width,
+        }
+    }
+
+    pub fn render(&self, text: &str) -> String {
+        let mut writer = Writer {
+            renderer: self,
+            lines: Vec::new(),
+            inline: String::new(),
+            styles: Vec::new(),
+            containers: Vec::new(),
+            lists: Vec::new(),
+            code: None,
+            table: None,
+            links: Vec::new(),
+            needs_blank: false,
+        };
+        for event in Parser::new_ext(text, PARSER_OPTIONS) {
+            writer.event(event);
+        }
+        writer.flush();
+
+        let mut output = writer.lines.join("\n");
+        output.truncate(output.trim_end().len());
+        output
+    }
+
+    /// Highlights code in `lang`. Code without a language, such as an
+    /// indented block, is shown like an inline code span.
+    fn highlight(&self, lang: &str, code: &str) -> Vec<String> {
+        if lang.is_empty() {
+            return code.lines().map(|line| format!("{}{}{}", CYAN, line, RESET)).collect();
+        }
+        let syntax = find_syntax(self.syntax_set, lang);
+        let mut highlighter = HighlightLines::new(syntax, self.theme);
+        LinesWithEndings::from(code)
+            .map(|line| match highlighter.highlight_line(line, self.syntax_set) {
+                Ok(ranges) => {
+                    let escaped = as_24_bit_terminal_escaped(&ranges[..], false);
+                    format!("{}{}", escaped.trim_end_matches(['\n', '\r']), RESET)
+                }
+                Err(_) => line.trim_end_matches(['\n', '\r']).to_string(),
+            })
+            .collect()
+    }
+}
+
+impl Writer<'_, '_> {
+    fn event(&mut self, event: Event) {
+        if let Some((_, code)) = self.code.as_mut() {
+            match event {
+                Event::Text(text) => code.push_str(&text),
+                Event::End(TagEnd::CodeBlock) => self.end_code_block(),
+                _ => {}
+            }
+            return;
+        }
+
+        match event {
+            Event::Start(tag) => self.start(tag),
+            Event::End(tag) => self.end(tag),
+            Event::Text(text) => self.inline.push_str(&text),
+            Event::Code(code) => {
+                self.inline.push_str(CYAN);
+                self.inline.push_str(&code);
+                self.restore_styles();
+            }
+            Event::InlineMath(math) | Event::DisplayMath(math) => self.inline.push_str(&math),
+            Event::Html(html) | Event::InlineHtml(html) => self.inline.push_str(&html),
+            Event::SoftBreak => self.inline.push(' '),
+            Event::HardBreak => self.inline.push('\n'),
+            Event::Rule => {
+                self.begin_block();
+                let width = self.renderer.width.saturating_sub(self.prefix_width()).max(MIN_WRAP_WIDTH);
+                let prefix = self.first_prefix();
+                self.lines.push(format!("{}{}{}{}", prefix, DIM, "─".repeat(width), RESET));
+                self.needs_blank = true;
+            }
+            Event::TaskListMarker(checked) => self.inline.push_str(if checked { "[x] " } else { "[ ] " }),
+            Event::FootnoteReference(label) => self.inline.push_str(&format!("[^{}]", label)),
+        }
+    }
+
+    fn start(&mut self, tag: Tag) {
+        match tag {
+            Tag::Paragraph => self.begin_block(),
+            Tag::Heading { level, .. } => {
+                self.begin_block();
+                match level {
+                    HeadingLevel::H1 => {
+                        self.push_style(BOLD);
+                        self.push_style(UNDERLINE);
+                    }
+                    HeadingLevel::H2 => self.push_style(BOLD),
+                    _ => {
+                        self.push_style(BOLD);
+                        self.push_style(ITALIC);
+                    }
+                }
+            }
+            Tag::BlockQuote(_) => {
+                self.begin_block();
+                self.containers.push(Container {
+                    marker: None,
+                    indent: "│ ".to_string(),
+                });
+            }
+            Tag::CodeBlock(kind) => {
+                self.begin_block();
+                let lang = match kind {
+                    CodeBlockKind::Fenced(info) => info.split_whitespace().next().unwrap_or("").to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                self.code = Some((lang, String::new()));
+            }
+            Tag::List(start) => {
+                // Nested lists in tight items follow the item text directly
+                if self.lists.is_empty() {
+                    self.begin_block();
+                } else {
+                    self.flush();
+                }
+                self.lists.push(start);
+            }
+            Tag::Item => {
+                self.flush();
+                let depth = self.lists.len().saturating_sub(1);
+                let marker = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        let marker = format!("{}. ", number);
+                        *number += 1;
+                        marker
+                    }
+                    _ => format!("{} ", BULLETS[depth % BULLETS.len()]),
+                };
+                self.containers.push(Container {
+                    indent: " ".repeat(marker.chars().count()),
+                    marker: Some(marker),
+                });
+            }
+            Tag::Table(alignments) => {
+                self.begin_block();
+                self.table = Some(Table {
+                    alignments,
+                    rows: Vec::new(),
+                    row: Vec::new(),
+                });
+            }
+            Tag::Emphasis => self.push_style(ITALIC),
+            Tag::Strong => self.push_style(BOLD),
+            Tag::Strikethrough => self.push_style(STRIKETHROUGH),
+            Tag::Link { dest_url, .. } => {
+                self.inline.push_str(&format!("\x1b]8;;{}\x1b\\", dest_url));
+                self.links.push((dest_url.to_string(), self.inline.len()));
+                self.push_style(BLUE);
+                self.push_style(UNDERLINE);
+            }
+            Tag::Image { dest_url, .. } => {
+                self.inline.push_str("[image: ");
+                self.links.push((dest_url.to_string(), self.inline.len()));
+            }
+            Tag::FootnoteDefinition(label) => {
+                self.begin_block();
+                self.inline.push_str(&format!("[^{}]: ", label));
+            }
+            _ => {}
+        }
+    }
+
+    fn end(&mut self, tag: TagEnd) {
+        match tag {
+            TagEnd::Paragraph | TagEnd::FootnoteDefinition => {
+                self.flush();
+                self.needs_blank = true;
+            }
+            TagEnd::Heading(_) => {
+                self.styles.clear();
+                self.inline.push_str(RESET);
+                self.flush();
+                self.needs_blank = true;
+            }
+            TagEnd::BlockQuote(_) => {
+                self.flush();
+                self.containers.pop();
+                self.needs_blank = true;
+            }
+            TagEnd::List(_) => {
+                self.flush();
+                self.lists.pop();
+                self.needs_blank = true;
+            }
+            TagEnd::Item => {
+                self.flush();
+                self.containers.pop();
+            }
+            TagEnd::TableCell => {
+                let cell = std::mem::take(&mut self.inline).trim().to_string();
+                if let Some(table) = self.table.as_mut() {
+                    table.row.push(cell);
+                }
+            }
+            TagEnd::TableHead | TagEnd::TableRow => {
+                if let Some(table) = self.table.as_mut() {
+                    let row = std::mem::take(&mut table.row);
+                    table.rows.push(row);
+                }
+            }
+            TagEnd::Table => {
+                self.end_table();
+                self.needs_blank = true;
+            }
+            TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough => self.pop_style(),
+            TagEnd::Link => {
+                self.styles.truncate(self.styles.len().saturating_sub(2));
+                self.restore_styles();
+                self.inline.push_str("\x1b]8;;\x1b\\");
+                if let Some((url, start)) = self.links.pop() {
+                    let text = strip_ansi(&self.inline[start..]);
+                    if text != url && !url.is_empty() {
+                        self.inline.push_str(&format!(" {}({})", DIM, url));
+                        self.restore_styles();
+                    }
+                }
+            }
+            TagEnd::Image => {
+                if let Some((url, _)) = self.links.pop() {
+                    self.inline.push_str(&format!("]({})", url));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn push_style(&mut self, style: &'static str) {
+        self.styles.push(style);
+        self.inline.push_str(style);
+    }
+
+    fn pop_style(&mut self) {
+        self.styles.pop();
+        self.restore_styles();
+    }
+
+    /// Resets all attributes, then re-applies the styles still open.
+    fn restore_styles(&mut self) {
+        self.inline.push_str(RESET);
+        for style in &self.styles {
+            self.inline.push_str(style);
+        }
+    }
+
+    /// Separates a new block from the previous one with a blank line.
+    fn begin_block(&mut self) {
+        self.flush();
+        if self.needs_blank && !self.lines.is_empty() {
+            let prefix: String = self.containers.iter().map(|c| c.indent.as_str()).collect();
+            self.lines.push(prefix.trim_end().to_string());
+        }
+        self.needs_blank = false;
+    }
+
+    fn prefix_width(&self) -> usize {
+        self.containers.iter().map(|c| c.indent.chars().count()).sum()
+    }
+
+    /// Prefix for the first line of a block, printing pending list markers.
+    fn first_prefix(&mut self) -> String {
+        self.containers
+            .iter_mut()
+            .map(|c| c.marker.take().unwrap_or_else(|| c.indent.clone()))
+            .collect()
+    }
+
+    /// Wraps and emits any pending inline text.
+    fn flush(&mut self) {
+        if self.inline.trim().is_empty() {
+            self.inline.clear();
+            return;
+        }
+        let text = std::mem::take(&mut self.inline);
+        let width = self.renderer.width.saturating_sub(self.prefix_width()).max(MIN_WRAP_WIDTH);
+        let first = self.first_prefix();
+        let rest: String = self.containers.iter().map(|c| c.indent.as_str()).collect();
+        for (i, line) in wrap(text.trim(), width).into_iter().enumerate() {
+            let prefix = if i == 0 { &first } else { &rest };
+            self.lines.push(format!("{}{}", prefix, line));
+        }
+    }
+
+    fn end_code_block(&mut self) {
+        let (lang, code) = self.code.take().unwrap_or_default();
+        let highlighted = self.renderer.highlight(&lang, &code);
+        let first = self.first_prefix();
+        let rest: String = self.containers.iter().map(|c| c.indent.as_str()).collect();
+        for (i, line) in highlighted.into_iter().enumerate() {
+            let prefix = if i == 0 { &first } else { &rest };
+            self.lines.push(format!("{}{}", prefix, line));
+        }
+        self.needs_blank = true;
+    }
+
+    fn end_table(&mut self) {
+        let table = match self.table.take() {
+            Some(table) => table,
+            None => return,
+        };
+        let columns = table.rows.iter().map(Vec::len).max().unwrap_or(0);
+        let mut widths = vec![0; columns];
+        for row in &table.rows {
+            for (i, cell) in row.iter().enumerate() {
+                widths[i] = widths[i].max(display_width(cell));
+            }
+        }
+
+        let pad = |cell: &str, width: usize, alignment: Alignment| {
+            let space = width - display_width(cell);
+            match alignment {
+                Alignment::Right => format!("{}{}", " ".repeat(space), cell),
+                Alignment::Center => format!("{}{}{}", " ".repeat(space / 2), cell, " ".repeat(space - space / 2)),
+                Alignment::Left | Alignment::None => format!("{}{}", cell, " ".repeat(space)),
+            }
+        };
+        let first = self.first_prefix();
+        let rest: String = self.containers.iter().map(|c| c.indent.as_str()).collect();
+
+        for (index, row) in table.rows.iter().enumerate() {
+            let cells: Vec<String> = (0..columns)
+                .map(|i| {
+                    let cell = row.get(i).map(String::as_str).unwrap_or("");
+                    let alignment = table.alignments.get(i).copied().unwrap_or(Alignment::None);
+                    if index == 0 {
+                        format!("{}{}{}", BOLD, pad(cell, widths[i], alignment), RESET)
+                    } else {
+                        pad(cell, widths[i], alignment)
+                    }
+                })
+                .collect();
+            let prefix = if index == 0 { &first } else { &rest };
+            self.lines.push(format!("{}{}", prefix, cells.join(" │ ").trim_end()));
+            if index == 0 {
+                let rule: Vec<String> = widths.iter().map(|w| "─".repeat(*w)).collect();
+                self.lines.push(format!("{}{}", rest, rule.join("─┼─")));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syntect::highlighting::ThemeSet;
+
+    fn render(text: &str) -> String {
+        let syntax_set = SyntaxSet::load_defaults_newlines();
+        let theme_set = ThemeSet::load_defaults();
+        let renderer = MarkdownRenderer::new(&syntax_set, &theme_set.themes["base16-ocean.dark"], 60);
+        // Make escape sequences visible in the snapshots
+        renderer.render(text).replace('\x1b', "␛")
+    }
+
+    #[test]
+    fn test_inline_styles() {
+        insta::assert_snapshot!(render(
+            "Some **bold**, *italic*, ***both***, ~~gone~~ and `code` text.\n\nA **bold `code` span** here."
+        ));
+    }
+
+    #[test]
+    fn test_headings_and_paragraphs() {
+        insta::assert_snapshot!(render(
+            "# Title\n\nIntro paragraph.\n\n## Section\n\nBody text\nwith a soft break.\n\n### Detail\n\nMore."
+        ));
+    }
+
+    #[test]
+    fn test_nested_lists() {
+        insta::assert_snapshot!(render(
+            "* first\n* second\n  * nested **bold**\n  * nested two\n    1. deep one\n    2. deep two\n* third\n\n3. three\n4. four\n\n- [x] done\n- [ ] todo"
+        ));
+    }
+
+    #[test]
+    fn test_loose_list_and_wrapping() {
+        insta::assert_snapshot!(render(
+            "1. A long list item that keeps going well past the sixty column limit so that it has to wrap.\n\n   A second paragraph in the same item.\n\n2. Short item."
+        ));
+    }
+
+    #[test]
+    fn test_blockquote_rule_and_links() {
+        insta::assert_snapshot!(render(
+            "> Quoted text with a [link](https://example.com) inside.\n>\n> > Nested quote.\n\n---\n\nSee <https://example.org> and ![logo](logo.png)."
+        ));
+    }
+
+    #[test]
+    fn test_table() {
+        insta::assert_snapshot!(render(
+            "| Command | Meaning | Size |\n|---|:---:|---:|\n| `ls -la` | list **all** files | 10 |\n| pwd | print directory | 2048 |"
+        ));
+    }
+
+    #[test]
+    fn test_code_blocks() {
+        insta::assert_snapshot!(render(
+            "Run this:\n\n```rust\nfn main() {\n    println!(\"hi\");\n}\n```\n\n    indented code\n\n- in a list:\n\n  ```\n  plain\n  ```"
+        ));
+    }
+
+    #[test]
+    fn test_wrap_ignores_escape_sequences() {
+        let text = format!("{}bold{} word another", BOLD, RESET);
+        assert_eq!(wrap(&text, 9), vec![format!("{}bold{} word", BOLD, RESET), "another".to_string()]);
+        assert_eq!(display_width("\x1b]8;;https://x.y\x1b\\link\x1b]8;;\x1b\\"), 4);
+    }
+
+    #[test]
+    fn test_wrap_measures_unicode_columns() {
+        assert_eq!(display_width("日本語"), 6);
+        assert_eq!(display_width("café"), 4);
+        assert_eq!(wrap("日本語のテキスト", 6), vec!["日本語", "のテキ", "スト"]);
+        assert_eq!(wrap("héllo wörld", 5), vec!["héllo", "wörld"]);
+    }
+
+    #[test]
+    fn test_wrap_reopens_styles_and_links() {
+        let link = "\x1b]8;;https://x.y\x1b\\";
+        let text = format!("{}{}one two{}\x1b]8;;\x1b\\", link, BOLD, RESET);
+        let lines = wrap(&text, 3);
+        assert_eq!(lines[0], format!("{}{}one\x1b]8;;\x1b\\{}", link, BOLD, RESET));
+        assert_eq!(lines[1], format!("{}{}two{}\x1b]8;;\x1b\\", BOLD, link, RESET));
+    }
+
+    #[test]
+    fn test_truncate_to_width() {
+        assert_eq!(truncate_to_width("short", 10), "short");
+        assert_eq!(truncate_to_width("abcdefghij", 8), "abcde...");
+        assert_eq!(truncate_to_width("日本語のテキスト", 9), "日本語...");
+    }
+
+    #[test]
+    fn test_extract_code_blocks() {
+        let text = "Run:\n\n```bash\nls -la\n```\n\nThen `inline` and\n\n    indented\n";
+        assert_eq!(code_blocks(text), vec!["ls -la\n", "indented\n"]);
+        assert!(code_blocks("No code here").is_empty());
+    }
+}
@@ -0,0 +1,405 @@
+use crate::structured::{self, DataFormat};
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Explains the cell format to the model when a notebook is edited.
+pub const EDIT_NOTES: &str = "This file is a Jupyter notebook, shown as its cell sources only. Each cell starts \
+    with a marker line such as `# %% [3] code` or `# %% [4] markdown`, where the number is the cell's index. \
+    Keep the marker lines of the cells you keep, unchanged. To add a cell, use `# %% [new] code` or \
+    `# %% [new] markdown`. To delete a cell, leave it out. Cells appear in the notebook in the order you output them. \
+    Lines inside a cell that look like markers are escaped with a leading backslash; keep the backslash.";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RenderedCell {
+    index: Option<usize>,
+    cell_type: String,
+    source: String,
+}
+
+/// A parsed `.ipynb` document. Only cell sources are exposed for editing;
+/// everything else is carried through unchanged.
+pub struct Notebook {
+    document: Value,
+}
+
+pub fn is_notebook(path: &str) -> bool {
+    DataFormat::for_path(path) == Some(DataFormat::Notebook)
+}
+
+fn marker_regex() -> Regex {
+    Regex::new(r"^# %% \[(\d+|new)\] (\w+)\s*$").unwrap()
+}
+
+// Source lines that would read as a marker, with any escaping backslashes
+fn escaped_marker_regex() -> Regex {
+    Regex::new(r"^\\*# %% \[(\d+|new)\] \w+\s*$").unwrap()
+}
+
+/// Prefixes marker-like source lines with a backslash so that they are not
+/// taken for cell boundaries. Lines that already start with backslashes get
+/// one more, so `unescape_line` can tell them apart.
+fn escape_source(source: &str) -> String {
+    let escaped = escaped_marker_regex();
+    source
+        .split_inclusive('\n')
+        .map(|line| {
+            if escaped.is_match(line.trim_end_matches('\n')) {
+                format!("\\{}", line)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect()
+}
+
+fn unescape_line(line: &str) -> &str {
+    if line.starts_with('\\') && escaped_marker_regex().is_match(line) {
+        &line[1..]
+    } else {
+        line
+    }
+}
+
+fn cell_source(cell: &Value) -> String {
+    match cell.get("source") {
+        Some(Value::String(source)) => source.clone(),
+        Some(Value::Array(lines)) => lines.iter().filter_map(Value::as_str).collect(),
+        _ => String::new(),
+    }
+}
+
+/// Stores `source` in the cell the way nbformat does: a list of lines, each
+/// ending with `\n` except the last, unless the cell used a single string.
+fn set_source(cell: &mut Map<String, Value>, source: &str) {
+    let as_string = matches!(cell.get("source"), Some(Value::String(_)));
+    let value = if as_string {
+        Value::String(source.to_string())
+    } else {
+        Value::Array(source.split_inclusive('\n').map(|line| Value::String(line.to_string())).collect())
+    };
+    cell.insert("source".to_string(), value);
+}
+
+fn clear_outputs(cell: &mut Map<String, Value>) {
+    cell.insert("outputs".to_string(), json!([]));
+    cell.insert("execution_count".to_string(), Value::Null);
+}
+
+/// Splits an edited rendering into cells. The blank line `render` puts
+/// between cells and the newline ending each source are dropped, so an
+/// unchanged rendering gives back the exact sources.
+fn parse_rendered(text: &str) -> Result<Vec<RenderedCell>, String> {
+    let marker = marker_regex();
+    let mut cells: Vec<RenderedCell> = Vec::new();
+    let mut body = String::new();
+
+    for line in text.split_inclusive('\n') {
+        let bare = line.trim_end_matches('\n').trim_end_matches('\r');
+        if let Some(captures) = marker.captures(bare) {
+            if let Some(cell) = cells.last_mut() {
+                let source = body.strip_suffix("\n\n").or_else(|| body.strip_suffix('\n')).unwrap_or(&body);
+                cell.source = source.to_string();
+            } else if !body.trim().is_empty() {
+                return Err("the edited notebook has text before the first cell marker".to_string());
+            }
+            body.clear();
+            cells.push(RenderedCell {
+                index: captures[1].parse().ok(),
+                cell_type: captures[2].to_string(),
+                source: String::new(),
+            });
+        } else {
+            body.push_str(unescape_line(bare));
+            body.push_str(&line[bare.len()..]);
+        }
+    }
+
+    match cells.last_mut() {
+        Some(cell) => cell.source = body.strip_suffix('\n').unwrap_or(&body).to_string(),
+        None => return Err("the edited notebook contains no cell markers".to_string()),
+    }
+    Ok(cells)
+}
+
+/// A new cell id, as nbformat 4.5 requires: eight hex digits not used by
+/// any other cell.
+fn new_cell_id(taken: &HashSet<String>) -> String {
+    let mut attempt = 0u64;
+    loop {
+        let mut hasher = DefaultHasher::new();
+        SystemTime::now().hash(&mut hasher);
+        std::process::id().hash(&mut hasher);
+        taken.len().hash(&mut hasher);
+        attempt.hash(&mut hasher);
+        let id = format!("{:08x}", hasher.finish() as u32);
+        if !taken.contains(&id) {
+            return id;
+        }
+        attempt += 1;
+    }
+}
+
+impl Notebook {
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let document = structured::parse(DataFormat::Notebook, content).map_err(|e| format!("Invalid notebook: {}", e))?;
+        Ok(Notebook { document })
+    }
+
+    fn cells(&self) -> &[Value] {
+        self.document.get("cells").and_then(Value::as_array).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// Cell sources with `# %% [index] type` markers, without outputs or metadata.
+    pub fn render(&self) -> String {
+        self.cells()
+            .iter()
+            .enumerate()
+            .map(|(index, cell)| {
+                let cell_type = cell.get("cell_type").and_then(Value::as_str).unwrap_or("code");
+                format!("# %% [{}] {}\n{}\n", index, cell_type, escape_source(&cell_source(cell)))
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Renders the cells with the markers of `edited`, the rendering this
+    /// notebook was built from, so that a later change to its JSON can be
+    /// compared with the original cells. Returns `None` if the change added
+    /// or removed cells.
+    pub fn render_like(&self, edited: &str) -> Option<String> {
+        let labels = parse_rendered(edited).ok()?;
+        if labels.len() != self.cells().len() {
+            return None;
+        }
+        let cells = labels
+            .iter()
+            .zip(self.cells())
+            .map(|(label, cell)| {
+                let index = label.index.map_or_else(|| "new".to_string(), |index| index.to_string());
+                let cell_type = cell.get("cell_type").and_then(Value::as_str).unwrap_or("code");
+                format!("# %% [{}] {}\n{}\n", index, cell_type, escape_source(&cell_source(cell)))
+            })
+            .collect::<Vec<String>>();
+        Some(cells.join("\n"))
+    }
+
+    /// Rebuilds the cell list from an edited rendering. Existing cells keep
+    /// their metadata and ids, and new cells get ids from nbformat 4.5 on;
+    /// code cells whose source changed lose their outputs when
+    /// `clear_stale_outputs` is set.
+    pub fn apply(&mut self, rendered: &str, clear_stale_outputs: bool) -> Result<(), String> {
+        let edited = parse_rendered(rendered)?;
+        let original = self.cells().to_vec();
+        let version = |key: &str| self.document.get(key).and_then(Value::as_u64).unwrap_or(0);
+        let needs_ids = (version("nbformat"), version("nbformat_minor")) >= (4, 5);
+        let mut ids: HashSet<String> = original
+            .iter()
+            .filter_map(|cell| cell.get("id").and_then(Value::as_str))
+            .map(str::to_string)
+            .collect();
+        let mut seen = HashSet::new();
+        let mut cells = Vec::with_capacity(edited.len());
+
+        for cell in edited {
+            if !matches!(cell.cell_type.as_str(), "code" | "markdown" | "raw") {
+                return Err(format!("unknown cell type `{}` in the edited notebook", cell.cell_type));
+            }
+
+            let mut object = match cell.index {
+                Some(index) => {
+                    let existing = original
+                        .get(index)
+                        .and_then(Value::as_object)
+                        .ok_or_else(|| format!("the edited notebook refers to cell {}, which does not exist", index))?;
+                    if !seen.insert(index) {
+                        return Err(format!("cell {} appears more than once in the edited notebook", index));
+                    }
+                    existing.clone()
+                }
+                None => {
+                    let mut object = Map::new();
+                    object.insert("metadata".to_string(), json!({}));
+                    object.insert("source".to_string(), json!([]));
+                    if needs_ids {
+                        let id = new_cell_id(&ids);
+                        ids.insert(id.clone());
+                        object.insert("id".to_string(), Value::String(id));
+                    }
+                    object
+                }
+            };
+
+            let previous_type = object.get("cell_type").and_then(Value::as_str).map(str::to_string);
+            // Trailing blank lines the model added or dropped do not count as a change
+            let previous_source = cell_source(&Value::Object(object.clone()));
+            let changed = previous_type.as_deref() != Some(cell.cell_type.as_str())
+                || previous_source.trim_end() != cell.source.trim_end();
+
+            object.insert("cell_type".to_string(), Value::String(cell.cell_type.clone()));
+            if changed || cell.index.is_none() {
+                set_source(&mut object, &cell.source);
+            }
+
+            if cell.cell_type == "code" {
+                if !object.contains_key("outputs") || (changed && clear_stale_outputs) {
+                    clear_outputs(&mut object);
+                }
+            } else {
+                object.remove("outputs");
+                object.remove("execution_count");
+            }
+            cells.push(Value::Object(object));
+        }
+
+        self.document["cells"] = Value::Array(cells);
+        Ok(())
+    }
+
+    /// Serializes the notebook the way Jupyter writes it: one-space indents
+    /// and sorted keys.
+    pub fn to_json(&self) -> Result<String, String> {
+        let mut buffer = Vec::new();
+        let formatter = serde_json::ser::PrettyFormatter::with_indent(b" ");
+        let mut serializer = serde_json::Serializer::with_formatter(&mut buffer, formatter);
+        serde::Serialize::serialize(&self.document, &mut serializer)
+            .map_err(|e| format!("Failed to serialize notebook: {}", e))?;
+        let mut text = String::from_utf8(buffer).map_err(|e| format!("Failed to serialize notebook: {}", e))?;
+        text.push('\n');
+        Ok(text)
+    }
+}
+
+/// Replaces a notebook's JSON with its rendered cell sources for use as
+/// prompt context. Other files and unparsable notebooks are returned as is.
+pub fn render_for_context(path: &Path, content: String) -> String {
+    if !is_notebook(&path.to_string_lossy()) {
+        return content;
+    }
+    match Notebook::parse(&content) {
+        Ok(notebook) => notebook.render(),
+        Err(_) => content,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTEBOOK: &str = r##"{
+ "cells": [
+  {
+   "cell_type": "markdown",
+   "metadata": {"tags": ["intro"]},
+   "source": ["# Title\n", "Some text"]
+  },
+  {
+   "cell_type": "code",
+   "execution_count": 3,
+   "metadata": {},
+   "outputs": [{"output_type": "display_data", "data": {"image/png": "iVBORw0KGgo="}}],
+   "source": ["x = 1\n", "print(x)"]
+  }
+ ],
+ "metadata": {"kernelspec": {"name": "python3"}},
+ "nbformat": 4,
+ "nbformat_minor": 5
+}"##;
+
+    #[test]
+    fn test_render_hides_outputs() {
+        let notebook = Notebook::parse(NOTEBOOK).unwrap();
+        assert_eq!(
+            notebook.render(),
+            "# %% [0] markdown\n# Title\nSome text\n\n# %% [1] code\nx = 1\nprint(x)\n"
+        );
+    }
+
+    #[test]
+    fn test_apply_edits_cells_and_keeps_metadata() {
+        let mut notebook = Notebook::parse(NOTEBOOK).unwrap();
+        let edited = "# %% [1] code\nx = 2\nprint(x)\n\n# %% [0] markdown\n# Title\nSome text\n\n# %% [new] code\nprint('done')\n";
+        notebook.apply(edited, true).unwrap();
+
+        let cells = notebook.cells();
+        assert_eq!(cells.len(), 3);
+        assert_eq!(cells[0]["source"], json!(["x = 2\n", "print(x)"]));
+        assert_eq!(cells[0]["outputs"], json!([]));
+        assert_eq!(cells[0]["execution_count"], Value::Null);
+        assert_eq!(cells[1]["metadata"], json!({"tags": ["intro"]}));
+        assert_eq!(cells[2]["cell_type"], "code");
+        assert_eq!(notebook.document["metadata"]["kernelspec"]["name"], "python3");
+        assert!(Notebook::parse(&notebook.to_json().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_apply_keeps_outputs_of_unchanged_cells() {
+        let mut notebook = Notebook::parse(NOTEBOOK).unwrap();
+        let rendered = notebook.render().replace("# Title", "# New title");
+        notebook.apply(&rendered, true).unwrap();
+        assert_eq!(notebook.cells()[1]["execution_count"], 3);
+        assert_eq!(notebook.cells()[0]["source"], json!(["# New title\n", "Some text"]));
+    }
+
+    #[test]
+    fn test_apply_rejects_unknown_cells() {
+        let mut notebook = Notebook::parse(NOTEBOOK).unwrap();
+        assert!(notebook.apply("# %% [7] code\nx\n", true).is_err());
+        assert!(notebook.apply("stray\n# %% [0] markdown\nx\n", true).is_err());
+        assert!(notebook.apply("# %% [0] markdown\na\n# %% [0] markdown\nb\n", true).is_err());
+    }
+
+    #[test]
+    fn test_round_trip_keeps_exact_sources() {
+        let source = r##"{"cells": [
+  {"cell_type": "code", "execution_count": 1, "id": "a1", "metadata": {}, "outputs": [{"output_type": "stream"}],
+   "source": ["x = 1\n", "\n"]},
+  {"cell_type": "code", "execution_count": 2, "id": "b2", "metadata": {}, "outputs": [{"output_type": "stream"}],
+   "source": ["text = \"\"\"\n", "# %% [0] code\n", "\\# %% [1] code\n", "\"\"\""]}
+ ], "metadata": {}, "nbformat": 4, "nbformat_minor": 5}"##;
+        let mut notebook = Notebook::parse(source).unwrap();
+        let original = notebook.document.clone();
+        let rendered = notebook.render();
+        assert!(rendered.contains("\n\\# %% [0] code\n\\\\# %% [1] code\n"));
+
+        notebook.apply(&rendered, true).unwrap();
+        assert_eq!(notebook.document, original);
+
+        // Dropping the blank lines between cells still leaves them unchanged
+        notebook.apply(&rendered.replace("\n\n\n", "\n"), true).unwrap();
+        assert_eq!(notebook.document, original);
+    }
+
+    #[test]
+    fn test_render_like_keeps_original_indices() {
+        let mut notebook = Notebook::parse(NOTEBOOK).unwrap();
+        let edited = "# %% [1] code\nx = 2\n\n# %% [new] markdown\nNote\n";
+        notebook.apply(edited, true).unwrap();
+        let repaired = Notebook::parse(&notebook.to_json().unwrap().replace("x = 2", "x = 3")).unwrap();
+        assert_eq!(
+            repaired.render_like(edited).unwrap(),
+            "# %% [1] code\nx = 3\n\n# %% [new] markdown\nNote\n"
+        );
+        assert_eq!(repaired.render_like("# %% [1] code\nx = 2\n"), None);
+    }
+
+    #[test]
+    fn test_new_cells_get_ids() {
+        let mut notebook = Notebook::parse(NOTEBOOK).unwrap();
+        let rendered = format!("{}\n# %% [new] code\na\n\n# %% [new] code\nb\n", notebook.render());
+        notebook.apply(&rendered, true).unwrap();
+        let first = notebook.cells()[2]["id"].as_str().unwrap().to_string();
+        let second = notebook.cells()[3]["id"].as_str().unwrap().to_string();
+        assert_eq!(first.len(), 8);
+        assert_ne!(first, second);
+
+        let mut older = Notebook::parse(&NOTEBOOK.replace("\"nbformat_minor\": 5", "\"nbformat_minor\": 4")).unwrap();
+        let rendered = format!("{}\n# %% [new] code\na\n", older.render());
+        older.apply(&rendered, true).unwrap();
+        assert!(older.cells()[2].get("id").is_none());
+    }
+}
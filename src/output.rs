@@ -0,0 +1,140 @@
+use crate::markdown;
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static COLORS_ENABLED: AtomicBool = AtomicBool::new(true);
+static JSON_MODE: AtomicBool = AtomicBool::new(false);
+
+/// The `--color` setting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            other => Err(format!("Invalid color setting `{}`; use auto, always or never", other)),
+        }
+    }
+}
+
+/// `auto` colors only a terminal, and only when `NO_COLOR` is unset or empty
+/// (see https://no-color.org).
+fn should_color(choice: ColorChoice, no_color: Option<&str>, is_terminal: bool) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => no_color.is_none_or(str::is_empty) && is_terminal,
+    }
+}
+
+/// Decides once, at startup, whether stdout gets escape codes. The same
+/// decision is applied to the `console` styles used by prompts and menus.
+pub fn init(choice: ColorChoice) {
+    let no_color = std::env::var("NO_COLOR").ok();
+    let enabled = should_color(choice, no_color.as_deref(), io::stdout().is_terminal());
+    COLORS_ENABLED.store(enabled, Ordering::Relaxed);
+    console::set_colors_enabled(enabled);
+    console::set_colors_enabled_stderr(should_color(choice, no_color.as_deref(), io::stderr().is_terminal()));
+}
+
+pub fn colors_enabled() -> bool {
+    COLORS_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Switches to `--format json`, where stdout carries a single JSON document.
+pub fn set_json_mode(enabled: bool) {
+    JSON_MODE.store(enabled, Ordering::Relaxed);
+}
+
+pub fn json_mode() -> bool {
+    JSON_MODE.load(Ordering::Relaxed)
+}
+
+/// Escape codes for the styles used in the output. All of them are empty
+/// when colors are disabled.
+pub struct Palette {
+    pub red: &'static str,
+    pub green: &'static str,
+    pub yellow: &'static str,
+    pub blue: &'static str,
+    pub cyan: &'static str,
+    pub bold: &'static str,
+    pub dim: &'static str,
+    pub reset: &'static str,
+}
+
+pub fn palette() -> Palette {
+    if colors_enabled() {
+        Palette {
+            red: "\x1b[31m",
+            green: "\x1b[32m",
+            yellow: "\x1b[33m",
+            blue: "\x1b[34m",
+            cyan: "\x1b[36m",
+            bold: "\x1b[1m",
+            dim: "\x1b[2m",
+            reset: "\x1b[0m",
+        }
+    } else {
+        Palette {
+            red: "",
+            green: "",
+            yellow: "",
+            blue: "",
+            cyan: "",
+            bold: "",
+            dim: "",
+            reset: "",
+        }
+    }
+}
+
+/// Prints a message for the user. In JSON mode it goes to stderr, so that
+/// stdout carries only the JSON document.
+pub fn say(message: impl fmt::Display) {
+    if json_mode() {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
+
+/// Prints a warning line marked with a yellow `!`.
+pub fn warn(message: &str) {
+    let Palette { yellow, reset, .. } = palette();
+    say(format_args!("{}!{} {}", yellow, reset, message));
+}
+
+/// Returns `text` unchanged when colors are enabled, or with its escape
+/// sequences removed when they are not.
+pub fn paint(text: &str) -> Cow<'_, str> {
+    if colors_enabled() {
+        Cow::Borrowed(text)
+    } else {
+        Cow::Owned(markdown::strip_ansi(text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_color() {
+        assert!(should_color(ColorChoice::Auto, None, true));
+        assert!(should_color(ColorChoice::Auto, Some(""), true));
+        assert!(!should_color(ColorChoice::Auto, Some("1"), true));
+        assert!(!should_color(ColorChoice::Auto, None, false));
+        assert!(should_color(ColorChoice::Always, Some("1"), false));
+        assert!(!should_color(ColorChoice::Never, None, true));
+    }
+}
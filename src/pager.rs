@@ -0,0 +1,103 @@
+use crate::markdown;
+use crate::output;
+use std::io::{self, IsTerminal, Write};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Pager used when neither the `pager` setting nor `$PAGER` is set.
+pub const DEFAULT_PAGER: &str = "less -R";
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Turns the pager off for this run, for `--no-pager`.
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Picks the pager command like git does: the `pager` setting, then
+/// `$PAGER`, then `less -R`. An empty or `cat` pager means no paging.
+fn resolve_command(configured: &str, env_pager: Option<&str>) -> Option<String> {
+    let command = if !configured.is_empty() {
+        configured
+    } else {
+        env_pager.unwrap_or(DEFAULT_PAGER)
+    };
+    let command = command.trim();
+    if command.is_empty() || command == "cat" {
+        None
+    } else {
+        Some(command.to_string())
+    }
+}
+
+/// Terminal rows needed to show `text` at `width` columns, counting lines
+/// that the terminal will soft-wrap.
+fn rows_needed(text: &str, width: usize) -> usize {
+    let width = width.max(1);
+    text.lines()
+        .map(|line| markdown::display_width(&markdown::strip_ansi(line)).div_ceil(width).max(1))
+        .sum()
+}
+
+/// Shows `text` through the pager when stdout is a terminal and the text is
+/// taller than it. Returns false when the text was not paged, in which case
+/// the caller prints it as usual.
+pub fn page(text: &str, configured: &str) -> bool {
+    if !ENABLED.load(Ordering::Relaxed) || output::json_mode() || !io::stdout().is_terminal() {
+        return false;
+    }
+    let Some((rows, columns)) = console::Term::stdout().size_checked() else {
+        return false;
+    };
+    if rows_needed(text, columns as usize) < rows as usize {
+        return false;
+    }
+    let env_pager = std::env::var("PAGER").ok();
+    let Some(command) = resolve_command(configured, env_pager.as_deref()) else {
+        return false;
+    };
+
+    let mut pager = Command::new("sh");
+    pager.arg("-c").arg(&command).stdin(Stdio::piped());
+    // Like git: keep colors, quit if the text fits after all, and leave it on
+    // screen when less exits instead of restoring the previous screen
+    if std::env::var_os("LESS").is_none() {
+        pager.env("LESS", "FRX");
+    }
+    if std::env::var_os("LV").is_none() {
+        pager.env("LV", "-c");
+    }
+    let Ok(mut child) = pager.spawn() else {
+        return false;
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // The pager closes its input when the user quits before the end
+        let _ = stdin.write_all(text.as_bytes());
+        let _ = stdin.write_all(b"\n");
+    }
+    let _ = child.wait();
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_command() {
+        assert_eq!(resolve_command("", None).as_deref(), Some(DEFAULT_PAGER));
+        assert_eq!(resolve_command("", Some("most")).as_deref(), Some("most"));
+        assert_eq!(resolve_command("less -S", Some("most")).as_deref(), Some("less -S"));
+        assert_eq!(resolve_command("cat", Some("most")), None);
+        assert_eq!(resolve_command("", Some("")), None);
+    }
+
+    #[test]
+    fn test_rows_needed() {
+        assert_eq!(rows_needed("one\n\nthree", 80), 3);
+        assert_eq!(rows_needed(&"x".repeat(81), 80), 2);
+        assert_eq!(rows_needed("\x1b[1mbold\x1b[0m", 4), 1);
+        assert_eq!(rows_needed("漢字", 2), 2);
+    }
+}
@@ -0,0 +1,130 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use similar::{ChangeTag, TextDiff};
+use std::time::Duration;
+
+/// Tokens used by the requests made for one command.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub output_tokens: u64,
+    pub total_tokens: u64,
+}
+
+impl TokenUsage {
+    pub fn add(&mut self, other: TokenUsage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.output_tokens += other.output_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+/// Wall-clock time spent waiting for the API and in total.
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct Timings {
+    pub requests: u32,
+    pub api_ms: u128,
+    pub total_ms: u128,
+}
+
+impl Timings {
+    pub fn new(requests: u32, api_time: Duration, total_time: Duration) -> Self {
+        Timings {
+            requests,
+            api_ms: api_time.as_millis(),
+            total_ms: total_time.as_millis(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileStatus {
+    Created,
+    Modified,
+    Unchanged,
+}
+
+/// A file touched by write mode, with its line diffstat.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FileChange {
+    pub path: String,
+    pub status: FileStatus,
+    pub additions: usize,
+    pub deletions: usize,
+}
+
+impl FileChange {
+    pub fn new(path: &str, existed: bool, original: &str, new_content: &str) -> Self {
+        let (additions, deletions) = line_stats(original, new_content);
+        let status = if !existed {
+            FileStatus::Created
+        } else if original == new_content {
+            FileStatus::Unchanged
+        } else {
+            FileStatus::Modified
+        };
+        FileChange {
+            path: path.to_string(),
+            status,
+            additions,
+            deletions,
+        }
+    }
+}
+
+/// Counts added and removed lines.
+pub fn line_stats(original: &str, new_content: &str) -> (usize, usize) {
+    let diff = TextDiff::from_lines(original, new_content);
+    diff.iter_all_changes().fold((0, 0), |(added, removed), change| match change.tag() {
+        ChangeTag::Insert => (added + 1, removed),
+        ChangeTag::Delete => (added, removed + 1),
+        ChangeTag::Equal => (added, removed),
+    })
+}
+
+/// Prints one JSON document for `mode` on stdout. `fields` holds the
+/// mode-specific fields, which are merged with the common ones.
+pub fn print_json(mode: &str, model: &str, fields: Value, usage: TokenUsage, timings: Timings) {
+    let mut document = json!({
+        "mode": mode,
+        "model": model,
+        "usage": usage,
+        "timings": timings,
+    });
+    if let (Some(document), Value::Object(fields)) = (document.as_object_mut(), fields) {
+        document.extend(fields);
+    }
+    println!("{}", serde_json::to_string_pretty(&document).unwrap_or_default());
+}
+
+/// Prints a JSON error document on stdout.
+pub fn print_json_error(mode: &str, error: &str) {
+    println!("{}", json!({ "mode": mode, "error": error }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_change() {
+        let change = FileChange::new("a.txt", true, "a\nb\nc\n", "a\nB\nc\nd\n");
+        assert_eq!((change.status, change.additions, change.deletions), (FileStatus::Modified, 2, 1));
+        assert_eq!(FileChange::new("a.txt", false, "", "x\n").status, FileStatus::Created);
+        assert_eq!(FileChange::new("a.txt", true, "x\n", "x\n").status, FileStatus::Unchanged);
+    }
+
+    #[test]
+    fn test_serialized_field_names() {
+        let usage = TokenUsage { prompt_tokens: 3, output_tokens: 4, total_tokens: 7 };
+        let timings = Timings::new(1, Duration::from_millis(20), Duration::from_millis(25));
+        assert_eq!(
+            serde_json::to_value(usage).unwrap(),
+            json!({"promptTokens": 3, "outputTokens": 4, "totalTokens": 7})
+        );
+        assert_eq!(serde_json::to_value(timings).unwrap(), json!({"requests": 1, "apiMs": 20, "totalMs": 25}));
+    }
+}
@@ -1,4 +1,7 @@
-use std::{env, process::Command};
+use crate::history::CommandEntry;
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::{env, thread};
 
 pub fn get_shell_info() -> (String, String) {
     match env::var("SHELL") {
@@ -32,6 +35,54 @@ pub fn get_pane_content() -> String {
     String::from_utf8_lossy(&output.stdout).trim().to_string()
 }
 
+/// Runs `command` with `shell -c` in the current directory. The output is
+/// shown as it is produced and also captured, ending with the exit code
+/// when the command fails.
+pub fn run_command(shell: &str, command: &str) -> Result<CommandEntry, String> {
+    let mut child = Command::new(shell)
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run {}: {}", shell, e))?;
+
+    let stdout = child.stdout.take().ok_or("Failed to capture the command's output")?;
+    let stderr = child.stderr.take().ok_or("Failed to capture the command's errors")?;
+    let errors = thread::spawn(move || tee(stderr, io::stderr()));
+    let mut output = tee(stdout, io::stdout());
+    output.push_str(&errors.join().unwrap_or_default());
+
+    let status = child.wait().map_err(|e| format!("Failed to wait for {}: {}", shell, e))?;
+    if !status.success() {
+        match status.code() {
+            Some(code) => output.push_str(&format!("\n[exit code {}]", code)),
+            None => output.push_str("\n[terminated by a signal]"),
+        }
+    }
+
+    Ok(CommandEntry {
+        command: command.to_string(),
+        output: output.trim().to_string(),
+    })
+}
+
+/// Copies `reader` to `writer` as it arrives and returns everything read.
+fn tee(mut reader: impl Read, mut writer: impl Write) -> String {
+    let mut captured = Vec::new();
+    let mut buffer = [0u8; 4096];
+    while let Ok(read) = reader.read(&mut buffer) {
+        if read == 0 {
+            break;
+        }
+        let _ = writer.write_all(&buffer[..read]);
+        let _ = writer.flush();
+        captured.extend_from_slice(&buffer[..read]);
+    }
+    String::from_utf8_lossy(&captured).to_string()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -39,8 +90,18 @@ mod tests {
     #[test]
     fn test_get_shell_info() {
         let (shell_name, shell_path) = get_shell_info();
-        assert!(shell_name.len() > 0);
-        assert!(shell_path.len() > 0);
+        assert!(!shell_name.is_empty());
+        assert!(!shell_path.is_empty());
         assert!(shell_name != "error");
     }
+
+    #[test]
+    fn test_run_command_captures_output() {
+        let entry = run_command("sh", "echo out; echo err >&2; exit 3").unwrap();
+        assert_eq!(entry.command, "echo out; echo err >&2; exit 3");
+        assert_eq!(entry.output, "out\nerr\n\n[exit code 3]");
+
+        let entry = run_command("sh", "pwd").unwrap();
+        assert_eq!(entry.output, env::current_dir().unwrap().display().to_string());
+    }
 }
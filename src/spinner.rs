@@ -0,0 +1,54 @@
+use std::io::{self, IsTerminal, Write};
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+async fn loading_animation(message: String, mut rx: oneshot::Receiver<()>) {
+    let frames = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+    let mut out = io::stderr();
+    let mut i = 0;
+    let _ = write!(out, "\x1b[?25l");
+    loop {
+        select! {
+            _ = tokio::time::sleep(Duration::from_millis(20)) => {
+                let _ = write!(out, "\r{} {}\x1b[K", frames[i % frames.len()], message);
+                let _ = out.flush();
+                i += 1;
+            }
+            _ = &mut rx => {
+                break;
+            }
+        }
+    }
+    let _ = write!(out, "\r\x1b[K");
+    let _ = write!(out, "\x1b[?25h");
+    let _ = out.flush();
+}
+
+pub struct Spinner {
+    tx: Option<oneshot::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Spinner {
+    /// Draws on stderr so stdout carries only the result. Nothing is drawn
+    /// when stderr is not a terminal.
+    pub fn start(message: &str) -> Self {
+        if !io::stderr().is_terminal() {
+            return Spinner { tx: None, handle: None };
+        }
+        let (tx, rx) = oneshot::channel();
+        let handle = tokio::spawn(loading_animation(message.to_string(), rx));
+        Spinner { tx: Some(tx), handle: Some(handle) }
+    }
+
+    /// Stops the animation and waits until the line has been cleared, so it
+    /// is safe to print or prompt the user afterwards.
+    pub async fn stop(self) {
+        if let (Some(tx), Some(handle)) = (self.tx, self.handle) {
+            let _ = tx.send(());
+            handle.await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use crate::output::{self, Palette};
+use serde::Deserialize;
+use serde_json::Value;
+use std::path::Path;
+
+// Longest rendering of a single value in a key diff
+const MAX_VALUE_WIDTH: usize = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataFormat {
+    Json,
+    Toml,
+    Yaml,
+    Notebook,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyChange {
+    Added(String, Value),
+    Removed(String, Value),
+    Changed(String, Value, Value),
+}
+
+impl DataFormat {
+    pub fn for_path(path: &str) -> Option<Self> {
+        let extension = Path::new(path).extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "json" => Some(DataFormat::Json),
+            "toml" => Some(DataFormat::Toml),
+            "yaml" | "yml" => Some(DataFormat::Yaml),
+            "ipynb" => Some(DataFormat::Notebook),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DataFormat::Json => "JSON",
+            DataFormat::Toml => "TOML",
+            DataFormat::Yaml => "YAML",
+            DataFormat::Notebook => "Jupyter notebook",
+        }
+    }
+
+    /// Whether `semantic_diff` gives a useful summary for this format.
+    pub fn has_key_diff(self) -> bool {
+        matches!(self, DataFormat::Json | DataFormat::Toml)
+    }
+}
+
+/// Parses `content` as `format`, returning the document as a JSON tree so
+/// that all formats can be compared the same way.
+pub fn parse(format: DataFormat, content: &str) -> Result<Value, String> {
+    match format {
+        DataFormat::Json => serde_json::from_str(content).map_err(|e| e.to_string()),
+        DataFormat::Toml => {
+            let table: toml::Table = toml::from_str(content).map_err(|e| e.to_string().trim_end().to_string())?;
+            Ok(toml_to_json(toml::Value::Table(table)))
+        }
+        DataFormat::Yaml => {
+            // Every document in a multi-document stream must parse; the last one is kept
+            let mut value = serde_yaml::Value::Null;
+            for document in serde_yaml::Deserializer::from_str(content) {
+                value = serde_yaml::Value::deserialize(document).map_err(|e| e.to_string())?;
+            }
+            // Mappings with non-string keys have no JSON equivalent
+            Ok(serde_json::to_value(value).unwrap_or(Value::Null))
+        }
+        DataFormat::Notebook => {
+            let value: Value = serde_json::from_str(content).map_err(|e| e.to_string())?;
+            check_notebook(&value)?;
+            Ok(value)
+        }
+    }
+}
+
+fn check_notebook(value: &Value) -> Result<(), String> {
+    if !value.get("nbformat").is_some_and(Value::is_u64) {
+        return Err("missing the integer \"nbformat\" field".to_string());
+    }
+    let cells = value
+        .get("cells")
+        .and_then(Value::as_array)
+        .ok_or("missing the \"cells\" array")?;
+    for (index, cell) in cells.iter().enumerate() {
+        if !cell.get("cell_type").is_some_and(Value::is_string) {
+            return Err(format!("cell {} has no \"cell_type\"", index));
+        }
+        if !cell.get("source").is_some_and(|s| s.is_string() || s.is_array()) {
+            return Err(format!("cell {} has no \"source\"", index));
+        }
+    }
+    Ok(())
+}
+
+fn toml_to_json(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::from(i),
+        toml::Value::Float(f) => Value::from(f),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(d) => Value::String(d.to_string()),
+        toml::Value::Array(items) => Value::Array(items.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(table) => Value::Object(table.into_iter().map(|(k, v)| (k, toml_to_json(v))).collect()),
+    }
+}
+
+/// Lists the keys that were added, removed or changed between two documents.
+/// Paths use dots for keys and brackets for array indices.
+pub fn semantic_diff(old: &Value, new: &Value) -> Vec<KeyChange> {
+    let mut changes = Vec::new();
+    diff_values("", old, new, &mut changes);
+    changes
+}
+
+fn join_key(prefix: &str, key: &str) -> String {
+    let simple = !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-');
+    let key = if simple { key.to_string() } else { format!("\"{}\"", key) };
+    if prefix.is_empty() {
+        key
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+fn diff_values(path: &str, old: &Value, new: &Value, changes: &mut Vec<KeyChange>) {
+    match (old, new) {
+        (Value::Object(old_map), Value::Object(new_map)) => {
+            for (key, old_value) in old_map {
+                let child = join_key(path, key);
+                match new_map.get(key) {
+                    Some(new_value) => diff_values(&child, old_value, new_value, changes),
+                    None => changes.push(KeyChange::Removed(child, old_value.clone())),
+                }
+            }
+            for (key, new_value) in new_map {
+                if !old_map.contains_key(key) {
+                    changes.push(KeyChange::Added(join_key(path, key), new_value.clone()));
+                }
+            }
+        }
+        (Value::Array(old_items), Value::Array(new_items)) => {
+            for (index, old_item) in old_items.iter().enumerate() {
+                let child = format!("{}[{}]", path, index);
+                match new_items.get(index) {
+                    Some(new_item) => diff_values(&child, old_item, new_item, changes),
+                    None => changes.push(KeyChange::Removed(child, old_item.clone())),
+                }
+            }
+            for (index, new_item) in new_items.iter().enumerate().skip(old_items.len()) {
+                changes.push(KeyChange::Added(format!("{}[{}]", path, index), new_item.clone()));
+            }
+        }
+        _ if old != new => {
+            let path = if path.is_empty() { "(document)" } else { path };
+            changes.push(KeyChange::Changed(path.to_string(), old.clone(), new.clone()));
+        }
+        _ => {}
+    }
+}
+
+fn render(value: &Value) -> String {
+    let text = value.to_string();
+    if text.chars().count() > MAX_VALUE_WIDTH {
+        format!("{}…", text.chars().take(MAX_VALUE_WIDTH - 1).collect::<String>())
+    } else {
+        text
+    }
+}
+
+/// Formats key changes for the terminal, one per line.
+pub fn format_key_changes(changes: &[KeyChange]) -> String {
+    let Palette { yellow, blue, red, reset, .. } = output::palette();
+    changes
+        .iter()
+        .map(|change| match change {
+            KeyChange::Added(path, value) => format!("{}+ {}{} = {}", blue, path, reset, render(value)),
+            KeyChange::Removed(path, value) => format!("{}- {}{} = {}", red, path, reset, render(value)),
+            KeyChange::Changed(path, old, new) => {
+                format!("{}~ {}{}: {} → {}", yellow, path, reset, render(old), render(new))
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_for_path() {
+        assert_eq!(DataFormat::for_path("config.YML"), Some(DataFormat::Yaml));
+        assert_eq!(DataFormat::for_path("a/b.ipynb"), Some(DataFormat::Notebook));
+        assert_eq!(DataFormat::for_path("main.rs"), None);
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_documents() {
+        assert!(parse(DataFormat::Json, "{\"a\": 1,}").is_err());
+        assert!(parse(DataFormat::Toml, "[server]\nport = ").is_err());
+        assert!(parse(DataFormat::Yaml, "a: [1, 2\nb: 3\n").is_err());
+        assert!(parse(DataFormat::Notebook, "{\"cells\": []}").is_err());
+        assert!(parse(DataFormat::Notebook, "{\"nbformat\": 4, \"cells\": [{\"cell_type\": \"code\", \"source\": []}]}").is_ok());
+        assert_eq!(parse(DataFormat::Yaml, "").unwrap(), Value::Null);
+    }
+
+    #[test]
+    fn test_parse_toml_datetime() {
+        let value = parse(DataFormat::Toml, "released = 2024-01-02\n").unwrap();
+        assert_eq!(value, json!({"released": "2024-01-02"}));
+    }
+
+    #[test]
+    fn test_semantic_diff() {
+        let old = parse(DataFormat::Toml, "[server]\nport = 80\nhost = \"a\"\n[features]\nlist = [1, 2]\n").unwrap();
+        let new = parse(DataFormat::Toml, "[server]\nport = 8080\nhost = \"a\"\ndebug = true\n[features]\nlist = [1]\n").unwrap();
+        assert_eq!(
+            semantic_diff(&old, &new),
+            vec![
+                KeyChange::Removed("features.list[1]".to_string(), json!(2)),
+                KeyChange::Changed("server.port".to_string(), json!(80), json!(8080)),
+                KeyChange::Added("server.debug".to_string(), json!(true)),
+            ]
+        );
+        assert!(semantic_diff(&old, &old).is_empty());
+    }
+}
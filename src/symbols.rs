@@ -0,0 +1,236 @@
+use regex::Regex;
+
+// Modifiers that may precede a definition keyword in the supported languages
+const MODIFIERS: &str = r#"(?:(?:pub(?:\([^)]*\))?|export|default|async|static|unsafe|extern(?:\s+"[^"]*")?|const|abstract|final|public|private|protected|internal|override|inline|virtual|sealed|data|open)\s+)*"#;
+
+// Definition keywords, including Rust impl blocks and Go methods with receivers
+const KEYWORDS: &str = r"(?:fn|def|class|struct|enum|trait|impl(?:<[^>]*>)?(?:\s+[\w:<>, ]+\s+for)?|function\*?|func(?:\s*\([^)]*\))?|interface|type|mod|module|union|object|protocol|macro_rules!)";
+
+/// Locates a function, class, struct or similar definition named `name`.
+/// Returns the 0-based line range `[start, end)` covering the definition and
+/// any doc comments, attributes or decorators directly above it.
+pub fn find_symbol(content: &str, name: &str) -> Option<(usize, usize)> {
+    let lines: Vec<&str> = content.lines().collect();
+    let name = regex::escape(name);
+
+    let definition = Regex::new(&format!(
+        r"^\s*{}{}\s+{}\b|^\s*(?:export\s+)?(?:const|let|var)\s+{}\s*[:=]",
+        MODIFIERS, KEYWORDS, name, name
+    ))
+    .ok()?;
+    // C-like functions and methods: `ReturnType name(args) {` without a trailing `;`
+    let c_like = Regex::new(&format!(r"^\s*[\w:<>*&\[\], ]*?\b{}\s*\([^;]*$", name)).ok()?;
+    let control = Regex::new(r"^\s*(?:if|for|while|switch|return|else|catch)\b").unwrap();
+
+    let start = lines
+        .iter()
+        .position(|line| definition.is_match(line))
+        .or_else(|| {
+            lines
+                .iter()
+                .position(|line| c_like.is_match(line) && !control.is_match(line))
+        })?;
+
+    let end = if lines[start].trim_end().ends_with(':') {
+        indentation_extent(&lines, start)
+    } else {
+        brace_extent(&lines, start).unwrap_or_else(|| indentation_extent(&lines, start))
+    };
+
+    Some((leading_attachments(&lines, start), end))
+}
+
+fn indent_of(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// End of an indentation-delimited block such as a Python `def`.
+fn indentation_extent(lines: &[&str], start: usize) -> usize {
+    let base = indent_of(lines[start]);
+    let mut end = start + 1;
+    for (i, line) in lines.iter().enumerate().skip(start + 1) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if indent_of(line) <= base {
+            break;
+        }
+        end = i + 1;
+    }
+    end
+}
+
+/// End of a brace-delimited block. Braces inside string literals and line
+/// comments are ignored. Returns `None` if no block is opened.
+fn brace_extent(lines: &[&str], start: usize) -> Option<usize> {
+    let mut depth = 0i32;
+    let mut opened = false;
+
+    for (i, line) in lines.iter().enumerate().skip(start) {
+        let chars: Vec<char> = line.chars().collect();
+        let mut j = 0;
+        let mut quote: Option<char> = None;
+
+        while j < chars.len() {
+            let c = chars[j];
+            match quote {
+                Some(q) => {
+                    if c == '\\' {
+                        j += 1;
+                    } else if c == q {
+                        quote = None;
+                    }
+                }
+                None => match c {
+                    '"' | '`' => quote = Some(c),
+                    // Only treat ' as a quote for char literals, not Rust lifetimes
+                    '\'' if is_char_literal(&chars[j..]) => quote = Some(c),
+                    '/' if chars.get(j + 1) == Some(&'/') => break,
+                    '{' => {
+                        depth += 1;
+                        opened = true;
+                    }
+                    '}' => {
+                        depth -= 1;
+                        if opened && depth == 0 {
+                            return Some(i + 1);
+                        }
+                    }
+                    ';' if !opened && depth == 0 => return Some(i + 1),
+                    _ => {}
+                },
+            }
+            j += 1;
+        }
+
+        // Give up on lines that clearly are not part of a signature
+        if !opened && i > start + 10 {
+            return None;
+        }
+    }
+
+    if opened {
+        Some(lines.len())
+    } else {
+        None
+    }
+}
+
+fn is_char_literal(chars: &[char]) -> bool {
+    match chars.get(1) {
+        Some('\\') => chars.get(3) == Some(&'\''),
+        Some(_) => chars.get(2) == Some(&'\''),
+        None => false,
+    }
+}
+
+/// Walks up from a definition over the doc comments, attributes and
+/// decorators directly above it. Block comments count only as a complete
+/// `/** ... */` block, and cell markers such as `# %%` and shebangs stop it.
+fn leading_attachments(lines: &[&str], start: usize) -> usize {
+    let mut first = start;
+    while first > 0 {
+        let previous = lines[first - 1].trim();
+        if previous.ends_with("*/") {
+            match doc_block_start(lines, first - 1) {
+                Some(block_start) => {
+                    first = block_start;
+                    continue;
+                }
+                None => break,
+            }
+        }
+        let marker = previous.starts_with("# %%") || previous.starts_with("// %%") || previous.starts_with("#!");
+        let decorator = previous
+            .strip_prefix('@')
+            .and_then(|rest| rest.chars().next())
+            .is_some_and(|c| c.is_alphabetic() || c == '_');
+        let attached = !marker
+            && (previous.starts_with("//")
+                || previous.starts_with("#[")
+                || previous.starts_with("# ")
+                || previous == "#"
+                || decorator);
+        if !attached {
+            break;
+        }
+        first -= 1;
+    }
+    first
+}
+
+/// The first line of the `/** ... */` doc block ending at `end`, if every
+/// line in between is part of it.
+fn doc_block_start(lines: &[&str], end: usize) -> Option<usize> {
+    let mut line = end;
+    loop {
+        let text = lines[line].trim();
+        if text.starts_with("/**") {
+            return Some(line);
+        }
+        if line == 0 || !(text.starts_with('*') || line == end) {
+            return None;
+        }
+        line -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_rust_function_with_docs() {
+        let source = "use std::fs;\n\n/// Reads it.\n#[inline]\npub fn read(path: &str) -> String {\n    let s = \"}\";\n    fs::read_to_string(path).unwrap()\n}\n\nfn other() {}\n";
+        assert_eq!(find_symbol(source, "read"), Some((2, 8)));
+        assert_eq!(find_symbol(source, "other"), Some((9, 10)));
+    }
+
+    #[test]
+    fn test_find_rust_impl_and_unit_struct() {
+        let source = "struct Marker;\n\nimpl Display for Point {\n    fn fmt(&self) {}\n}\n";
+        assert_eq!(find_symbol(source, "Marker"), Some((0, 1)));
+        assert_eq!(find_symbol(source, "Point"), Some((2, 5)));
+    }
+
+    #[test]
+    fn test_find_python_def_with_decorator() {
+        let source = "import os\n\n@cache\ndef load(name):\n    if name:\n\n        return 1\n    return 2\n\ndef after():\n    pass\n";
+        assert_eq!(find_symbol(source, "load"), Some((2, 8)));
+    }
+
+    #[test]
+    fn test_find_js_and_go() {
+        let js = "export class Store {\n  get() { return '{'; }\n}\nconst helper = () => {\n  return 1;\n};\n";
+        assert_eq!(find_symbol(js, "Store"), Some((0, 3)));
+        assert_eq!(find_symbol(js, "helper"), Some((3, 6)));
+
+        let go = "package main\n\nfunc (s *Server) Start() error {\n\treturn nil\n}\n";
+        assert_eq!(find_symbol(go, "Start"), Some((2, 5)));
+    }
+
+    #[test]
+    fn test_find_c_like_method() {
+        let java = "class A {\n    public static int compute(int x) {\n        return x;\n    }\n}\n";
+        assert_eq!(find_symbol(java, "compute"), Some((1, 4)));
+    }
+
+    #[test]
+    fn test_missing_symbol() {
+        assert_eq!(find_symbol("fn a() {}\n", "missing"), None);
+    }
+
+    #[test]
+    fn test_attachments_stop_at_code_and_markers() {
+        let c = "int x;\n*p = 0;\n// Frees it.\nvoid release(int *p) {\n}\n";
+        assert_eq!(find_symbol(c, "release"), Some((2, 5)));
+
+        let js = "/**\n * Adds.\n */\nfunction add(a, b) {\n}\n";
+        assert_eq!(find_symbol(js, "add"), Some((0, 5)));
+        let not_doc = "/* licence */\nfunction add(a, b) {\n}\n";
+        assert_eq!(find_symbol(not_doc, "add"), Some((1, 3)));
+
+        let script = "#!/usr/bin/env python3\n# %% [0] code\n# Loads it.\ndef load():\n    pass\n";
+        assert_eq!(find_symbol(script, "load"), Some((2, 5)));
+    }
+}
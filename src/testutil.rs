@@ -0,0 +1,37 @@
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A fresh directory under the system temp directory for a test, removed
+/// again when it goes out of scope.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates `huh-<name>-<pid>`, emptying any copy left by an earlier run.
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("huh-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,158 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use syntect::highlighting::{Theme, ThemeSet};
+
+/// Theme setting that picks a light or dark theme from the terminal background.
+pub const AUTO: &str = "auto";
+
+const DARK_THEME: &str = "base16-ocean.dark";
+const LIGHT_THEME: &str = "InspiredGitHub";
+
+// Longest reply to the background color query that is read
+const MAX_REPLY_BYTES: usize = 64;
+
+/// Directory searched for user `.tmTheme` files.
+pub fn themes_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("huh").join("themes"))
+}
+
+/// The built-in syntect themes plus any `.tmTheme` files in `themes_dir`,
+/// which are named after their file stem.
+pub fn load_themes() -> ThemeSet {
+    let mut themes = ThemeSet::load_defaults();
+    if let Some(dir) = themes_dir().filter(|dir| dir.is_dir()) {
+        if let Err(e) = themes.add_from_folder(&dir) {
+            eprintln!("Warning: failed to load themes from {}: {}", dir.display(), e);
+        }
+    }
+    themes
+}
+
+/// Names of every theme that can be configured, sorted.
+pub fn available_themes() -> Vec<String> {
+    load_themes().themes.into_keys().collect()
+}
+
+/// Looks up the configured theme. `auto` asks the terminal for its
+/// background color; unknown names fall back to the default dark theme.
+pub fn resolve(name: &str) -> Theme {
+    let mut themes = load_themes();
+    let name = if name == AUTO {
+        match detect_light_background() {
+            Some(true) => LIGHT_THEME,
+            _ => DARK_THEME,
+        }
+    } else {
+        name
+    };
+
+    match themes.themes.remove(name) {
+        Some(theme) => theme,
+        None => {
+            eprintln!("Warning: unknown theme `{}`, using {}", name, DARK_THEME);
+            themes.themes.remove(DARK_THEME).unwrap_or_default()
+        }
+    }
+}
+
+/// Whether the terminal has a light background, from `COLORFGBG` or else
+/// an OSC 11 query. `None` when neither gives an answer.
+fn detect_light_background() -> Option<bool> {
+    if let Ok(value) = std::env::var("COLORFGBG") {
+        if let Some(light) = parse_colorfgbg(&value) {
+            return Some(light);
+        }
+    }
+    if !io::stdout().is_terminal() {
+        return None;
+    }
+    let reply = query_background().ok()?;
+    let (red, green, blue) = parse_osc11_reply(&reply)?;
+    Some(0.2126 * red + 0.7152 * green + 0.0722 * blue > 0.5)
+}
+
+/// `COLORFGBG` is `fg;bg` (sometimes `fg;default;bg`) using the 16 ANSI
+/// color indexes, where 7 and 15 are the light backgrounds.
+fn parse_colorfgbg(value: &str) -> Option<bool> {
+    let background: u8 = value.rsplit(';').next()?.parse().ok()?;
+    Some(matches!(background, 7 | 15))
+}
+
+fn stty(tty: &File, args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::from(tty.try_clone()?))
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("stty failed"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Sends the OSC 11 background color query and reads the reply, waiting
+/// at most 200ms for terminals that do not answer.
+fn query_background() -> io::Result<String> {
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    let saved = stty(&tty, &["-g"])?;
+    stty(&tty, &["-echo", "-icanon", "min", "0", "time", "2"])?;
+
+    let result = (|| {
+        tty.write_all(b"\x1b]11;?\x07")?;
+        tty.flush()?;
+        let mut reply = Vec::new();
+        let mut byte = [0u8; 1];
+        while reply.len() < MAX_REPLY_BYTES && tty.read(&mut byte)? == 1 {
+            reply.push(byte[0]);
+            if byte[0] == 0x07 || reply.ends_with(b"\x1b\\") {
+                break;
+            }
+        }
+        Ok(String::from_utf8_lossy(&reply).to_string())
+    })();
+
+    stty(&tty, &[&saved])?;
+    result
+}
+
+/// Parses `ESC ] 11 ; rgb:RRRR/GGGG/BBBB` into components between 0 and 1.
+/// Each component may have one to four hex digits.
+fn parse_osc11_reply(reply: &str) -> Option<(f64, f64, f64)> {
+    let start = reply.find("rgb:")? + 4;
+    let body = reply[start..].trim_end_matches('\x07').trim_end_matches("\x1b\\");
+    let mut components = body.split('/').map(|hex| {
+        let digits = hex.get(..hex.len().min(4))?;
+        let value = u32::from_str_radix(digits, 16).ok()?;
+        let max = (1u32 << (4 * digits.len())) - 1;
+        Some(value as f64 / max as f64)
+    });
+    Some((components.next()??, components.next()??, components.next()??))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_osc11_reply() {
+        assert_eq!(parse_osc11_reply("\x1b]11;rgb:ffff/ffff/ffff\x07"), Some((1.0, 1.0, 1.0)));
+        assert_eq!(parse_osc11_reply("\x1b]11;rgb:00/00/00\x1b\\"), Some((0.0, 0.0, 0.0)));
+        assert_eq!(parse_osc11_reply("garbage"), None);
+    }
+
+    #[test]
+    fn test_parse_colorfgbg() {
+        assert_eq!(parse_colorfgbg("0;15"), Some(true));
+        assert_eq!(parse_colorfgbg("15;default;0"), Some(false));
+        assert_eq!(parse_colorfgbg("bad"), None);
+    }
+
+    #[test]
+    fn test_builtin_themes_are_available() {
+        let themes = available_themes();
+        assert!(themes.contains(&DARK_THEME.to_string()));
+        assert!(themes.contains(&LIGHT_THEME.to_string()));
+    }
+}
@@ -0,0 +1,213 @@
+use crate::fileio::{self, FileSnapshot};
+use crate::guard;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const MANIFEST: &str = "manifest.json";
+
+/// A file write that is part of a multi-file operation.
+pub struct PendingWrite {
+    pub path: String,
+    pub bytes: Vec<u8>,
+    pub snapshot: Option<FileSnapshot>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    description: String,
+    files: Vec<JournalEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JournalEntry {
+    path: String,
+    existed: bool,
+}
+
+fn journal_dir() -> Result<PathBuf, String> {
+    Ok(dirs::data_local_dir()
+        .ok_or("Unable to determine data directory")?
+        .join("huh")
+        .join("undo"))
+}
+
+fn before_copy(dir: &Path, index: usize) -> PathBuf {
+    dir.join(format!("before-{}", index))
+}
+
+fn after_copy(dir: &Path, index: usize) -> PathBuf {
+    dir.join(format!("after-{}", index))
+}
+
+/// Puts `bytes` back at `path`, keeping the permissions of the current file.
+fn restore_file(path: &str, bytes: &[u8]) -> Result<(), String> {
+    let snapshot = fileio::read_snapshot(path).ok().flatten();
+    match snapshot {
+        Some(snapshot) => fileio::write_atomic(path, bytes, Some(&snapshot)),
+        None => fs::write(path, bytes).map_err(|e| format!("Failed to restore {}: {}", path, e)),
+    }
+}
+
+fn revert(dir: &Path, entries: &[JournalEntry]) -> Vec<String> {
+    let mut errors = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let result = if entry.existed {
+            fs::read(before_copy(dir, index))
+                .map_err(|e| format!("Failed to read the backup of {}: {}", entry.path, e))
+                .and_then(|bytes| restore_file(&entry.path, &bytes))
+        } else {
+            fs::remove_file(&entry.path).map_err(|e| format!("Failed to remove {}: {}", entry.path, e))
+        };
+        if let Err(e) = result {
+            errors.push(e);
+        }
+    }
+    errors
+}
+
+/// Writes every file as one operation. The original contents are journaled
+/// first so that `undo_last` can revert the whole operation, and if any write
+/// fails the files already written are restored. The journal holds a single
+/// operation, so this replaces the journal of the previous one.
+pub fn apply_all(writes: &[PendingWrite], description: &str) -> Result<(), String> {
+    apply_all_in(&journal_dir()?, writes, description)
+}
+
+fn apply_all_in(dir: &Path, writes: &[PendingWrite], description: &str) -> Result<(), String> {
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create undo journal: {}", e))?;
+
+    let mut entries = Vec::with_capacity(writes.len());
+    for (index, write) in writes.iter().enumerate() {
+        let path = guard::normalize(Path::new(&write.path)).to_string_lossy().to_string();
+        let existed = Path::new(&path).exists();
+        if existed {
+            fs::copy(&path, before_copy(dir, index))
+                .map_err(|e| format!("Failed to back up {}: {}", write.path, e))?;
+        }
+        fs::write(after_copy(dir, index), &write.bytes)
+            .map_err(|e| format!("Failed to write undo journal: {}", e))?;
+        entries.push(JournalEntry { path, existed });
+    }
+
+    let manifest = Manifest {
+        description: description.to_string(),
+        files: entries,
+    };
+    let json = serde_json::to_string_pretty(&manifest).map_err(|e| format!("Failed to write undo journal: {}", e))?;
+    fs::write(dir.join(MANIFEST), json).map_err(|e| format!("Failed to write undo journal: {}", e))?;
+
+    for (index, write) in writes.iter().enumerate() {
+        if let Err(e) = fileio::write_atomic(&write.path, &write.bytes, write.snapshot.as_ref()) {
+            let errors = revert(dir, &manifest.files[..index]);
+            let _ = fs::remove_dir_all(dir);
+            let mut message = format!(
+                "Failed to write {}: {}. The {} files already written were restored",
+                write.path, e, index
+            );
+            for error in errors {
+                message.push_str(&format!("\n  {}", error));
+            }
+            return Err(message);
+        }
+    }
+    Ok(())
+}
+
+/// Reverts the last multi-file operation, returning its description and
+/// the files that were restored. Refuses if any of them changed since.
+pub fn undo_last() -> Result<(String, Vec<String>), String> {
+    undo_last_in(&journal_dir()?)
+}
+
+fn undo_last_in(dir: &Path) -> Result<(String, Vec<String>), String> {
+    let manifest = fs::read_to_string(dir.join(MANIFEST)).map_err(|_| "There is nothing to undo".to_string())?;
+    let manifest: Manifest =
+        serde_json::from_str(&manifest).map_err(|e| format!("The undo journal is damaged: {}", e))?;
+
+    let changed: Vec<&str> = manifest
+        .files
+        .iter()
+        .enumerate()
+        .filter(|(index, entry)| fs::read(&entry.path).ok() != fs::read(after_copy(dir, *index)).ok())
+        .map(|(_, entry)| entry.path.as_str())
+        .collect();
+    if !changed.is_empty() {
+        return Err(format!(
+            "These files changed after the edit, so it was not undone:\n  {}",
+            changed.join("\n  ")
+        ));
+    }
+
+    let errors = revert(dir, &manifest.files);
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    let _ = fs::remove_dir_all(dir);
+    Ok((manifest.description, manifest.files.into_iter().map(|entry| entry.path).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn pending(path: &Path, bytes: &[u8]) -> PendingWrite {
+        let path = path.to_string_lossy().to_string();
+        PendingWrite {
+            snapshot: fileio::read_snapshot(&path).unwrap(),
+            path,
+            bytes: bytes.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_apply_and_undo() {
+        let dir = TempDir::new("undo-apply");
+        let journal = dir.join("journal");
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        fs::write(&a, "old a\n").unwrap();
+
+        let writes = vec![pending(&a, b"new a\n"), pending(&b, b"new b\n")];
+        apply_all_in(&journal, &writes, "test edit").unwrap();
+        assert_eq!(fs::read_to_string(&a).unwrap(), "new a\n");
+        assert_eq!(fs::read_to_string(&b).unwrap(), "new b\n");
+
+        let (description, files) = undo_last_in(&journal).unwrap();
+        assert_eq!(description, "test edit");
+        assert_eq!(files.len(), 2);
+        assert_eq!(fs::read_to_string(&a).unwrap(), "old a\n");
+        assert!(!b.exists());
+        assert!(undo_last_in(&journal).is_err());
+    }
+
+    #[test]
+    fn test_undo_refuses_after_later_changes() {
+        let dir = TempDir::new("undo-changed");
+        let journal = dir.join("journal");
+        let a = dir.join("a.txt");
+        fs::write(&a, "old\n").unwrap();
+
+        apply_all_in(&journal, &[pending(&a, b"new\n")], "test edit").unwrap();
+        fs::write(&a, "edited by hand\n").unwrap();
+        assert!(undo_last_in(&journal).is_err());
+        assert_eq!(fs::read_to_string(&a).unwrap(), "edited by hand\n");
+    }
+
+    #[test]
+    fn test_failed_write_restores_earlier_files() {
+        let dir = TempDir::new("undo-rollback");
+        let journal = dir.join("journal");
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        fs::write(&a, "old a\n").unwrap();
+        fs::write(&b, "old b\n").unwrap();
+
+        let writes = vec![pending(&a, b"new a\n"), pending(&b, b"new b\n")];
+        // b changes after it was read, so its write is refused
+        fs::write(&b, "changed b\n").unwrap();
+        assert!(apply_all_in(&journal, &writes, "test edit").is_err());
+        assert_eq!(fs::read_to_string(&a).unwrap(), "old a\n");
+        assert_eq!(fs::read_to_string(&b).unwrap(), "changed b\n");
+    }
+}
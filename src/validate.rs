@@ -0,0 +1,302 @@
+use crate::git;
+use crate::guard;
+use ignore::WalkBuilder;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::process::Command;
+
+// Diagnostics beyond this many bytes are cut before being sent back to the model
+const MAX_DIAGNOSTIC_BYTES: usize = 8000;
+
+// Projects larger than this are not copied for a project-level check
+const MAX_COPY_FILES: usize = 20_000;
+const MAX_COPY_BYTES: u64 = 200 * 1024 * 1024;
+
+// Numbers the scratch copies of one process, since batch edits check at once
+static NEXT_COPY: AtomicUsize = AtomicUsize::new(0);
+
+pub enum CheckOutcome {
+    Passed,
+    Failed(String),
+    Skipped(String),
+}
+
+/// Built-in check commands, keyed by file extension. `{file}` is replaced
+/// with a temporary copy of the edited file; commands without it run in a
+/// scratch copy of the project with the edit in place.
+pub fn default_check_commands() -> BTreeMap<String, String> {
+    [
+        ("rs", "cargo check --quiet --message-format short"),
+        ("py", "python3 -m py_compile {file}"),
+        ("sh", "shellcheck {file}"),
+        ("bash", "shellcheck {file}"),
+        ("json", "jq . {file}"),
+        ("js", "node --check {file}"),
+        ("mjs", "node --check {file}"),
+    ]
+    .iter()
+    .map(|(ext, cmd)| (ext.to_string(), cmd.to_string()))
+    .collect()
+}
+
+pub fn check_command_for<'a>(commands: &'a BTreeMap<String, String>, file_path: &str) -> Option<&'a str> {
+    let extension = Path::new(file_path).extension()?.to_str()?.to_lowercase();
+    commands
+        .get(&extension)
+        .map(String::as_str)
+        .filter(|cmd| !cmd.trim().is_empty())
+}
+
+fn quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', r"'\''"))
+}
+
+fn file_dir(file_path: &str) -> PathBuf {
+    match Path::new(file_path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() && parent.exists() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Runs `command` against `content` as if it were the new version of
+/// `file_path`. Commands with `{file}` check a temporary copy next to the
+/// file, which is removed afterwards; Python bytecode for it goes to a
+/// temporary directory. Other commands run in a scratch copy of the
+/// project (see `ScratchCopy`), so the real file is never touched.
+pub async fn run_check(command: &str, file_path: &str, content: &[u8]) -> CheckOutcome {
+    let dir = file_dir(file_path);
+
+    if command.contains("{file}") {
+        let name = Path::new(file_path).file_name().and_then(|n| n.to_str()).unwrap_or("file");
+        let temp_path = dir.join(format!(".huh-check-{}-{}", std::process::id(), name));
+        if let Err(e) = fs::write(&temp_path, content) {
+            return CheckOutcome::Skipped(format!("could not write a temporary copy: {}", e));
+        }
+        let pycache = std::env::temp_dir().join(format!("huh-check-pycache-{}", std::process::id()));
+        let outcome = run_shell(&command.replace("{file}", &quote(&temp_path)), &dir, Some(&pycache)).await;
+        let _ = fs::remove_file(&temp_path);
+        let _ = fs::remove_dir_all(&pycache);
+        // Point diagnostics at the real file rather than the temporary copy
+        return match outcome {
+            CheckOutcome::Failed(output) => {
+                CheckOutcome::Failed(output.replace(&temp_path.to_string_lossy().to_string(), file_path))
+            }
+            other => other,
+        };
+    }
+
+    let target = guard::normalize(Path::new(file_path));
+    let root = git::repo_root(file_path)
+        .map(|root| guard::normalize(&root))
+        .filter(|root| target.starts_with(root))
+        .unwrap_or_else(|| guard::normalize(&dir));
+    let copy = match ScratchCopy::create(&root, &target, content) {
+        Ok(copy) => copy,
+        Err(e) => return CheckOutcome::Skipped(e),
+    };
+    let relative_dir = target.parent().and_then(|parent| parent.strip_prefix(&root).ok()).unwrap_or(Path::new(""));
+    match run_shell(command, &copy.root.join(relative_dir), None).await {
+        // Point diagnostics at the real project rather than the copy
+        CheckOutcome::Failed(output) => CheckOutcome::Failed(
+            output.replace(&copy.root.to_string_lossy().to_string(), &root.to_string_lossy()),
+        ),
+        other => other,
+    }
+}
+
+/// A temporary copy of a project with an edit applied, for checks such as
+/// `cargo check` that need the whole project. Files ignored by git are not
+/// copied; ignored directories next to the edited file or above it, such
+/// as `target` or `node_modules`, are linked instead so that build caches
+/// and dependencies are shared. The copy is removed when dropped.
+struct ScratchCopy {
+    root: PathBuf,
+}
+
+impl ScratchCopy {
+    fn create(project: &Path, target: &Path, content: &[u8]) -> Result<Self, String> {
+        let root = std::env::temp_dir().join(format!(
+            "huh-check-{}-{}",
+            std::process::id(),
+            NEXT_COPY.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&root);
+        // Created first so that a failure below still removes what was copied
+        let copy = ScratchCopy { root };
+        fs::create_dir_all(&copy.root).map_err(|e| format!("could not create a copy of the project: {}", e))?;
+
+        let (mut files, mut bytes) = (0, 0);
+        let entries = WalkBuilder::new(project)
+            .hidden(false)
+            .filter_entry(|entry| entry.file_name() != ".git")
+            .build()
+            .filter_map(Result::ok);
+        for entry in entries {
+            let Ok(relative) = entry.path().strip_prefix(project) else {
+                continue;
+            };
+            let destination = copy.root.join(relative);
+            let file_type = entry.file_type();
+            if file_type.is_some_and(|t| t.is_dir()) {
+                fs::create_dir_all(&destination).map_err(|e| format!("could not copy the project: {}", e))?;
+            } else if file_type.is_some_and(|t| t.is_file()) {
+                files += 1;
+                bytes += fs::copy(entry.path(), &destination).map_err(|e| format!("could not copy the project: {}", e))?;
+                if files > MAX_COPY_FILES || bytes > MAX_COPY_BYTES {
+                    return Err(format!(
+                        "{} is too large to copy for checking; use {{file}} in the command to check only the file",
+                        project.display()
+                    ));
+                }
+            }
+        }
+
+        let mut linked = target.parent();
+        while let Some(dir) = linked.filter(|dir| dir.starts_with(project)) {
+            copy.link_ignored_dirs(project, dir);
+            linked = dir.parent();
+        }
+
+        let destination = copy.root.join(target.strip_prefix(project).unwrap_or(target));
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("could not copy the project: {}", e))?;
+        }
+        fs::write(&destination, content).map_err(|e| format!("could not write the edit to the copy: {}", e))?;
+        Ok(copy)
+    }
+
+    /// Links the directories in `dir` that were not copied.
+    fn link_ignored_dirs(&self, project: &Path, dir: &Path) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            let Ok(relative) = path.strip_prefix(project) else {
+                continue;
+            };
+            let destination = self.root.join(relative);
+            if entry.file_name() == ".git" || !path.is_dir() || destination.exists() {
+                continue;
+            }
+            #[cfg(unix)]
+            let _ = std::os::unix::fs::symlink(&path, &destination);
+        }
+    }
+}
+
+impl Drop for ScratchCopy {
+    fn drop(&mut self) {
+        // Links are removed without following them
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+async fn run_shell(command: &str, dir: &Path, pycache: Option<&Path>) -> CheckOutcome {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command).current_dir(dir).kill_on_drop(true);
+    if let Some(pycache) = pycache {
+        shell.env("PYTHONPYCACHEPREFIX", pycache);
+    }
+    let output = match shell.output().await {
+        Ok(output) => output,
+        Err(e) => return CheckOutcome::Skipped(format!("could not run `{}`: {}", command, e)),
+    };
+
+    if output.status.success() {
+        return CheckOutcome::Passed;
+    }
+
+    // 127 is the shell's "command not found"
+    if output.status.code() == Some(127) {
+        return CheckOutcome::Skipped(format!("`{}` is not installed", command.split_whitespace().next().unwrap_or(command)));
+    }
+
+    let mut diagnostics = String::from_utf8_lossy(&output.stderr).to_string();
+    diagnostics.push_str(&String::from_utf8_lossy(&output.stdout));
+    let mut diagnostics = diagnostics.trim().to_string();
+    if diagnostics.len() > MAX_DIAGNOSTIC_BYTES {
+        let mut cut = MAX_DIAGNOSTIC_BYTES;
+        while !diagnostics.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        diagnostics.truncate(cut);
+        diagnostics.push_str("\n... (truncated)");
+    }
+    if diagnostics.is_empty() {
+        diagnostics = format!("`{}` exited with {}", command, output.status);
+    }
+    CheckOutcome::Failed(diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_check_command_for() {
+        let mut commands = default_check_commands();
+        commands.insert("toml".to_string(), String::new());
+        assert_eq!(check_command_for(&commands, "src/main.rs"), Some("cargo check --quiet --message-format short"));
+        assert_eq!(check_command_for(&commands, "script.PY"), Some("python3 -m py_compile {file}"));
+        assert_eq!(check_command_for(&commands, "Cargo.toml"), None);
+        assert_eq!(check_command_for(&commands, "Makefile"), None);
+    }
+
+    #[tokio::test]
+    async fn test_run_check_with_temp_copy() {
+        let dir = TempDir::new("validate");
+        let file = dir.join("data.txt").to_string_lossy().to_string();
+
+        assert!(matches!(run_check("grep -q ok {file}", &file, b"ok\n").await, CheckOutcome::Passed));
+        match run_check("grep ok {file} || { echo \"bad content in {file}\" >&2; exit 1; }", &file, b"no\n").await {
+            CheckOutcome::Failed(diagnostics) => assert!(diagnostics.contains(&file)),
+            _ => panic!("expected the check to fail"),
+        }
+        assert!(matches!(
+            run_check("definitely-not-a-real-command-xyz {file}", &file, b"").await,
+            CheckOutcome::Skipped(_)
+        ));
+        assert!(!Path::new(&file).exists());
+    }
+
+    #[tokio::test]
+    async fn test_project_check_uses_a_copy() {
+        let dir = TempDir::new("validate-project");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("cache")).unwrap();
+        // In a git repository the whole work tree is copied, and ignored directories are linked
+        let initialized = std::process::Command::new("git").args(["init", "-q"]).current_dir(&dir).status();
+        if !initialized.is_ok_and(|status| status.success()) {
+            return;
+        }
+        fs::write(dir.join(".gitignore"), "cache/\n").unwrap();
+        fs::write(dir.join("build.txt"), "built\n").unwrap();
+        fs::write(dir.join("cache/data"), "cached\n").unwrap();
+        let file = dir.join("src/lib.txt").to_string_lossy().to_string();
+        fs::write(&file, "original\n").unwrap();
+
+        // The check sees the edit and the rest of the project, the real file is untouched
+        let command = "grep -q edited lib.txt && grep -q built ../build.txt && grep -q cached ../cache/data";
+        assert!(matches!(run_check(command, &file, b"edited\n").await, CheckOutcome::Passed));
+        assert_eq!(fs::read_to_string(&file).unwrap(), "original\n");
+
+        match run_check("echo \"failed in $(pwd)\" >&2; exit 1", &file, b"edited\n").await {
+            CheckOutcome::Failed(diagnostics) => assert!(diagnostics.contains(&dir.to_string_lossy().to_string())),
+            _ => panic!("expected the check to fail"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_python_check_leaves_no_bytecode() {
+        let dir = TempDir::new("validate-py");
+        let file = dir.join("script.py").to_string_lossy().to_string();
+        let outcome = run_check("python3 -m py_compile {file}", &file, b"x = 1\n").await;
+        if matches!(outcome, CheckOutcome::Passed) {
+            assert!(!dir.join("__pycache__").exists());
+        }
+    }
+}